@@ -0,0 +1,53 @@
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+use btleplug::api::BDAddr;
+use lighthouse::{Error, Version};
+use serde::{Deserialize, Serialize};
+
+/// A basestation remembered in `~/.cache/lighthouse/devices.json`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Device {
+    pub name:          String,
+    pub address:       BDAddr,
+    pub peripheral_id: String,
+    pub version:       Version,
+}
+
+pub fn path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("lighthouse").join("devices.json"))
+}
+
+/// Load the cached devices, or none when the cache doesn't exist
+pub fn load() -> Result<Vec<Device>, Error> {
+    let Some(path) = path() else {
+        return Ok(Vec::new());
+    };
+
+    match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).map_err(Error::Json),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(Error::Io(error)),
+    }
+}
+
+/// Add or replace the devices in the cache by address
+pub fn update(devices: Vec<Device>) -> Result<(), Error> {
+    let Some(path) = path() else {
+        return Ok(());
+    };
+
+    let mut cached = load()?;
+    cached.retain(|cached| {
+        !devices
+            .iter()
+            .any(|device| device.address == cached.address)
+    });
+    cached.extend(devices);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(Error::Io)?;
+    }
+
+    let json = serde_json::to_string_pretty(&cached).map_err(Error::Json)?;
+    fs::write(path, json).map_err(Error::Io)
+}
@@ -0,0 +1,119 @@
+use std::{collections::HashMap, fs, io::ErrorKind, path::PathBuf};
+
+use btleplug::api::BDAddr;
+use lighthouse::{Error, State};
+use serde::Deserialize;
+
+/// Defaults loaded from `~/.config/lighthouse/config.toml`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Index of the Bluetooth adapter to use
+    pub adapter:        Option<usize>,
+    /// Seconds to scan for basestations
+    pub timeout:        Option<u64>,
+    /// V1: Basestation BSID
+    pub bsid:           Option<String>,
+    /// Basestations targeted when no --name or --mac is given
+    pub stations:       Vec<Station>,
+    /// Steps of `lighthouse sequence` when none are given
+    pub sequence:       Vec<String>,
+    /// Names or addresses of the basestations in each --group
+    pub groups:         HashMap<String, Vec<String>>,
+    /// Presets run by `lighthouse profile <name>`
+    pub profiles:       HashMap<String, Profile>,
+    /// Refuse to control basestations unless a target is given, the same as --require-target
+    pub require_target: bool,
+}
+
+/// A state and the basestations to put into it, all of them when no filter is given
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub state:   State,
+    #[serde(default)]
+    pub name:    Vec<String>,
+    #[serde(default)]
+    pub mac:     Vec<String>,
+    #[serde(default)]
+    pub group:   Vec<String>,
+    /// V1: Basestation BSID, overriding the one of the config
+    pub bsid:    Option<String>,
+    /// Seconds to scan for basestations, overriding the one of the config
+    pub timeout: Option<u64>,
+}
+
+/// A basestation of the config, by its name, its address or both
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Station {
+    pub name: Option<String>,
+    pub mac:  Option<String>,
+}
+
+impl Station {
+    /// Whether the basestation with this name and address is this entry, every field given has
+    /// to match
+    pub fn matches(&self, name: &str, address: BDAddr) -> bool {
+        let named = self
+            .name
+            .as_deref()
+            .is_none_or(|entry| name.contains(entry));
+        let mac = self
+            .mac
+            .as_deref()
+            .is_none_or(|mac| lighthouse::parse_address(mac).is_ok_and(|mac| mac == address));
+
+        named && mac
+    }
+}
+
+/// Whether the basestation is one of the configured stations, all of them when there are none
+pub fn targets(stations: &[Station], name: &str, address: BDAddr) -> bool {
+    stations.is_empty() || stations.iter().any(|entry| entry.matches(name, address))
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("lighthouse").join("config.toml"))
+    }
+
+    /// Load the config file, or the defaults when it doesn't exist
+    pub fn load() -> Result<Self, Error> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+
+        match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(Error::TomlParse),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(Error::Io(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stations_match_any_entry() {
+        let config: Config = toml::from_str(
+            r#"
+            [[stations]]
+            name = "LHB-1A2B3C4D"
+
+            [[stations]]
+            mac = "AA:BB:CC:DD:EE:FF"
+            "#,
+        )
+        .unwrap();
+
+        let address = BDAddr::from([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+        let other = BDAddr::from([0x11; 6]);
+
+        assert!(targets(&config.stations, "LHB-1A2B3C4D", other));
+        assert!(targets(&config.stations, "LHB-5E6F7A8B", address));
+        assert!(!targets(&config.stations, "LHB-5E6F7A8B", other));
+    }
+}
@@ -0,0 +1,126 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{Path, State as Shared},
+    http::StatusCode,
+    routing::{get, post},
+    Json,
+    Router,
+};
+use btleplug::{api::Central, platform::Adapter};
+use lighthouse::{BaseStation, Error, Options, State, Version};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::{Outcome, Report};
+
+/// Shared by the request handlers
+struct Context {
+    adapter: Adapter,
+    bsid:    Option<String>,
+    options: Options,
+}
+
+/// Body of `POST /stations/{name}/state`
+#[derive(Debug, Deserialize)]
+struct Body {
+    state: State,
+}
+
+type Response<T> = Result<Json<T>, (StatusCode, String)>;
+
+/// Serve the REST API until the process is stopped, the adapter must already be scanning
+pub async fn serve(
+    adapter: Adapter,
+    address: SocketAddr,
+    bsid: Option<String>,
+    options: Options,
+) -> Result<(), Error> {
+    let context = Arc::new(Context {
+        adapter,
+        bsid,
+        options,
+    });
+
+    let router = Router::new()
+        .route("/stations", get(stations))
+        .route("/stations/{name}/state", post(set_state))
+        .with_state(context);
+
+    let listener = TcpListener::bind(address).await.map_err(Error::Io)?;
+    info!("Listening on http://{address}");
+
+    axum::serve(listener, router).await.map_err(Error::Io)
+}
+
+/// Respond with the error of a failed Bluetooth operation
+fn failed(error: Error) -> (StatusCode, String) {
+    (StatusCode::BAD_GATEWAY, error.to_string())
+}
+
+/// The basestations the adapter has discovered so far
+async fn known(adapter: &Adapter) -> Result<Vec<BaseStation>, Error> {
+    let mut stations = Vec::new();
+    for peripheral in adapter.peripherals().await.map_err(Error::Btle)?.iter() {
+        if let Some(station) = BaseStation::from_peripheral(peripheral).await? {
+            stations.push(station);
+        }
+    }
+
+    Ok(stations)
+}
+
+/// `GET /stations`: List the basestations, with the power state of each V2 basestation
+async fn stations(Shared(context): Shared<Arc<Context>>) -> Response<Vec<Report>> {
+    let mut reports = Vec::new();
+    for station in known(&context.adapter).await.map_err(failed)? {
+        let mut report = Report::new(&station);
+        if station.version == Version::V2 {
+            let timeout = context.options.timeout;
+            match lighthouse::get_state(&context.adapter, &station, timeout).await {
+                Ok(state) => report.state = Some(state),
+                Err(error) => report.result = Some(Outcome::Failed(error.to_string())),
+            }
+        }
+
+        reports.push(report);
+    }
+
+    Ok(Json(reports))
+}
+
+/// `POST /stations/{name}/state`: Set the power state of the basestation
+async fn set_state(
+    Shared(context): Shared<Arc<Context>>,
+    Path(name): Path<String>,
+    Json(body): Json<Body>,
+) -> Response<Report> {
+    let state = body.state;
+    let stations = known(&context.adapter).await.map_err(failed)?;
+    let Some(station) = stations.into_iter().find(|station| station.name == name) else {
+        return Err((StatusCode::NOT_FOUND, "Unknown basestation".into()));
+    };
+
+    // Requests the basestation can't carry out aren't Bluetooth failures
+    if !station.version.supports(state) {
+        let message = format!("{} basestations don't support {state}", station.version);
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, message));
+    }
+
+    let bsid = context.bsid.as_deref();
+    if station.version == Version::V1 && bsid.is_none() {
+        let message = "V1 basestations can only be controlled with --bsid";
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, message.into()));
+    }
+
+    lighthouse::set_state(&context.adapter, &station, state, bsid, &context.options)
+        .await
+        .map_err(failed)?;
+
+    let mut report = Report::new(&station);
+    report.state = Some(state);
+    report.result = Some(Outcome::Ok);
+
+    Ok(Json(report))
+}
@@ -1,23 +1,504 @@
-use std::time::Duration;
+use std::{
+    collections::BTreeSet,
+    fmt,
+    future::Future,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 use btleplug::{
-    api::{Central, Peripheral, WriteType},
-    platform::{Adapter, PeripheralId},
+    api::{
+        BDAddr,
+        Central,
+        CentralEvent,
+        CharPropFlags,
+        Characteristic,
+        Manager as _,
+        Peripheral,
+        ScanFilter,
+        Service,
+        WriteType,
+    },
+    platform::{self, Adapter, Manager, PeripheralId},
 };
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::time;
-use uuid::Uuid;
+use tracing::{debug, info, warn};
+use uuid::{uuid, Uuid};
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("BtleError")]
+    #[error("Bluetooth error: {0}")]
     Btle(#[from] btleplug::Error),
-    #[error("StdError")]
+    #[error("Invalid number: {0}")]
     Std(#[from] std::num::ParseIntError),
-    #[error("UuidError")]
+    #[error("UUID parse error: {0}")]
     Uuid(#[from] uuid::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("TOML parse error: {0}")]
+    TomlParse(#[from] toml::de::Error),
+    #[error("TOML error: {0}")]
+    TomlSerialize(#[from] toml::ser::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "mqtt")]
+    #[error("MQTT error: {0}")]
+    Mqtt(#[from] rumqttc::ClientError),
+    #[cfg(feature = "mqtt")]
+    #[error("MQTT connection error: {0}")]
+    MqttConnection(#[from] Box<rumqttc::ConnectionError>),
+    #[error("Timed out {operation} {id}")]
+    Timeout {
+        id:        PeripheralId,
+        operation: &'static str,
+    },
+    #[error("No basestations were found")]
+    NotFound,
+    #[error("Characteristic {0} not found")]
+    CharacteristicNotFound(Uuid),
+    #[error("{failed} of {total} basestations failed")]
+    Partial { failed: usize, total: usize },
     #[error("{0}")]
     Message(&'static str),
+    #[error("{0}")]
+    Owned(String),
+}
+
+/// Progress of [`Lighthouse::control`], e.g. to show how many basestations were found
+#[derive(Debug)]
+pub enum DiscoveryEvent {
+    /// The basestation was found, it is controlled once the scan time elapsed
+    Discovered(BaseStation),
+    /// Controlling the basestation finished
+    Controlled(BaseStation, Result<(), Error>),
+}
+
+/// Longest write the default ATT MTU of 23 bytes allows, btleplug doesn't report the MTU
+pub const MAX_WRITE_LENGTH: usize = 20;
+
+/// Fail when the data doesn't fit into a single write
+fn check_length(data: &[u8]) -> Result<(), Error> {
+    if data.len() > MAX_WRITE_LENGTH {
+        return Err(Error::Owned(format!(
+            "Command is {} bytes, a write may be at most {MAX_WRITE_LENGTH}",
+            data.len()
+        )));
+    }
+
+    Ok(())
+}
+
+/// V1: Service advertised by the basestation
+pub const V1_SERVICE_UUID: Uuid = uuid!("0000cb00-0000-1000-8000-00805f9b34fb");
+/// V2: Service advertised by the basestation
+pub const V2_SERVICE_UUID: Uuid = uuid!("00001523-1212-efde-1523-785feabcd124");
+/// V1: Characteristic that accepts power commands
+pub const V1_UUID: Uuid = uuid!("0000cb01-0000-1000-8000-00805f9b34fb");
+/// V2: Characteristic holding the power state
+pub const V2_UUID: Uuid = uuid!("00001525-1212-efde-1523-785feabcd124");
+/// V2: Characteristic holding the RF channel
+pub const V2_CHANNEL_UUID: Uuid = uuid!("00001524-1212-efde-1523-785feabcd124");
+/// V2: Characteristic that blinks the LED when written
+pub const V2_IDENTIFY_UUID: Uuid = uuid!("00008421-1212-efde-1523-785feabcd124");
+/// Device Information: Serial number string
+pub const SERIAL_NUMBER_UUID: Uuid = uuid!("00002a25-0000-1000-8000-00805f9b34fb");
+/// Device Information: Firmware revision string
+pub const FIRMWARE_REVISION_UUID: Uuid = uuid!("00002a26-0000-1000-8000-00805f9b34fb");
+/// Device Information: Hardware revision string
+pub const HARDWARE_REVISION_UUID: Uuid = uuid!("00002a27-0000-1000-8000-00805f9b34fb");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Off,
+    On,
+    Standby,
+}
+
+impl FromStr for State {
+    type Err = Error;
+
+    fn from_str(state: &str) -> Result<Self, Self::Err> {
+        match state.to_uppercase().as_str() {
+            "OFF" => Ok(Self::Off),
+            "ON" => Ok(Self::On),
+            "STANDBY" => Ok(Self::Standby),
+            _ => Err(Error::Owned(format!(
+                "Unknown state {state}, Available: [OFF|ON|STANDBY]"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Off => write!(f, "off"),
+            Self::On => write!(f, "on"),
+            Self::Standby => write!(f, "standby"),
+        }
+    }
+}
+
+/// Basestation generation, detected from the advertised name
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    /// Detect the version from the name prefix, see [`classify`]
+    pub fn from_name(name: &str) -> Option<Self> {
+        classify(name)
+    }
+
+    /// Whether basestations of this version can be put into the state, V1 has no standby
+    pub fn supports(self, state: State) -> bool {
+        self == Self::V2 || state != State::Standby
+    }
+}
+
+/// Detect the version from the advertised name, `HTC BS` prefixes V1 and `LHB-` V2 basestations
+pub fn classify(name: &str) -> Option<Version> {
+    if name.starts_with("HTC BS") {
+        Some(Version::V1)
+    } else if name.starts_with("LHB-") {
+        Some(Version::V2)
+    } else {
+        None
+    }
+}
+
+/// Detect the version from the discovered GATT services, by their power characteristic
+///
+/// A cross-check for [`classify`], for basestations whose name doesn't follow the usual prefix.
+pub fn classify_services(services: &BTreeSet<Service>) -> Option<Version> {
+    let has = |uuid| {
+        services
+            .iter()
+            .any(|service| service.characteristics.iter().any(|c| c.uuid == uuid))
+    };
+
+    if has(V1_UUID) {
+        Some(Version::V1)
+    } else if has(V2_UUID) {
+        Some(Version::V2)
+    } else {
+        None
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::V1 => write!(f, "V1"),
+            Self::V2 => write!(f, "V2"),
+        }
+    }
+}
+
+/// A discovered basestation
+#[derive(Clone, Debug)]
+pub struct BaseStation {
+    pub name:    String,
+    pub address: BDAddr,
+    pub id:      PeripheralId,
+    pub version: Version,
+    /// Signal strength in dBm when it was last seen
+    pub rssi:    Option<i16>,
+}
+
+impl BaseStation {
+    /// Classify the peripheral by its advertised name, `None` if it isn't a basestation
+    pub async fn from_peripheral(peripheral: &platform::Peripheral) -> Result<Option<Self>, Error> {
+        let Some(properties) = peripheral.properties().await.map_err(Error::Btle)? else {
+            return Ok(None);
+        };
+
+        let Some(name) = properties.local_name else {
+            return Ok(None);
+        };
+
+        let Some(version) = classify(&name) else {
+            let services = [V1_SERVICE_UUID, V2_SERVICE_UUID];
+            if properties
+                .services
+                .iter()
+                .any(|uuid| services.contains(uuid))
+            {
+                info!("{name} advertises a basestation service with an unknown name, please report it");
+            } else {
+                debug!("Ignoring {name}, not a basestation");
+            }
+
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            name,
+            address: properties.address,
+            id: peripheral.id(),
+            version,
+            rssi: properties.rssi,
+        }))
+    }
+
+    /// Fail unless this is a V2 basestation
+    fn require_v2(&self) -> Result<(), Error> {
+        match self.version {
+            Version::V1 => Err(Error::Message("V2: Not supported by V1 basestations")),
+            Version::V2 => Ok(()),
+        }
+    }
+}
+
+/// V2: Revisions and serial number from the standard Device Information service
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DeviceInfo {
+    pub firmware: Option<String>,
+    pub hardware: Option<String>,
+    pub serial:   Option<String>,
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields = [
+            ("firmware", &self.firmware),
+            ("hardware", &self.hardware),
+            ("serial", &self.serial),
+        ];
+
+        let fields = fields
+            .iter()
+            .filter_map(|(label, value)| value.as_ref().map(|value| format!("{label} {value}")))
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", fields.join(", "))
+    }
+}
+
+/// Options controlling how state commands are sent
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Log the command instead of writing it
+    pub dry_run: bool,
+
+    /// Number of times a failed write is retried with exponential backoff
+    pub retries: u32,
+
+    /// V2: Read the state back after writing and fail if it didn't change
+    pub confirm: bool,
+
+    /// V2: How long to wait after writing before reading the state back
+    pub post_delay: Duration,
+
+    /// Number of times the state command is sent, even when it succeeded
+    pub repeat: u32,
+
+    /// How long connecting to or writing to the basestation may take
+    pub timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            dry_run:    false,
+            retries:    0,
+            confirm:    false,
+            post_delay: Duration::from_millis(500),
+            repeat:     1,
+            timeout:    Duration::from_secs(5),
+        }
+    }
+}
+
+/// The Bluetooth operations basestations are controlled with, so they can be mocked in tests
+pub trait BleBackend: Sync {
+    /// A connection to one peripheral
+    type Link: BleLink + Send + Sync;
+
+    /// Connect to the peripheral and discover its services
+    fn open(
+        &self,
+        id: &PeripheralId,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Self::Link, Error>> + Send;
+}
+
+/// A connection opened by a [`BleBackend`]
+pub trait BleLink {
+    /// Write the data to the characteristic
+    fn write(&self, uuid: Uuid, data: &[u8]) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Read the characteristic
+    fn read(&self, uuid: &Uuid) -> impl Future<Output = Result<Vec<u8>, Error>> + Send;
+
+    /// Disconnect from the peripheral
+    fn close(self) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+impl BleBackend for Adapter {
+    type Link = Connection;
+
+    async fn open(&self, id: &PeripheralId, timeout: Duration) -> Result<Connection, Error> {
+        Connection::open(self, id, timeout).await
+    }
+}
+
+impl BleLink for Connection {
+    async fn write(&self, uuid: Uuid, data: &[u8]) -> Result<(), Error> {
+        Connection::write(self, uuid, data).await
+    }
+
+    async fn read(&self, uuid: &Uuid) -> Result<Vec<u8>, Error> {
+        Connection::read(self, uuid).await
+    }
+
+    async fn close(self) -> Result<(), Error> {
+        self.disconnect().await
+    }
+}
+
+/// The connection of one operation on a basestation, opened by the first write or read
+///
+/// Repeated writes, both halves of a reboot and the read confirming a write share it, rather than
+/// each connecting, discovering the services and disconnecting again.
+struct Session<'a, B: BleBackend> {
+    backend: &'a B,
+    id:      &'a PeripheralId,
+    options: &'a Options,
+    link:    tokio::sync::OnceCell<B::Link>,
+}
+
+impl<'a, B: BleBackend> Session<'a, B> {
+    fn new(backend: &'a B, id: &'a PeripheralId, options: &'a Options) -> Self {
+        Self {
+            backend,
+            id,
+            options,
+            link: tokio::sync::OnceCell::new(),
+        }
+    }
+
+    /// The connection, opened now unless an earlier write or read did
+    async fn link(&self) -> Result<&B::Link, Error> {
+        let open = || self.backend.open(self.id, self.options.timeout);
+        self.link.get_or_try_init(open).await
+    }
+
+    /// Write the command with retries, or only log it on a dry run
+    async fn send(&self, cmd: &[u8], uuid: Uuid) -> Result<(), Error> {
+        // Checked up front as well, so a dry run rejects what the write would
+        check_length(cmd)?;
+
+        let id = self.id;
+        if self.options.dry_run {
+            let hex = cmd
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<String>();
+            info!("Dry run: Would write {hex} to {uuid} on {id}");
+            return Ok(());
+        }
+
+        let mut attempt = 0;
+        loop {
+            // A failed connection isn't kept, so the next attempt connects again
+            let written = match self.link().await {
+                Ok(link) => link.write(uuid, cmd).await,
+                Err(error) => Err(error),
+            };
+
+            match written {
+                Err(error) if attempt < self.options.retries => {
+                    let delay = Duration::from_millis(200 * 2u64.pow(attempt.min(10)));
+                    attempt += 1;
+
+                    debug!(
+                        "Retrying write to {id} in {delay:?} ({attempt}/{}): {error}",
+                        self.options.retries
+                    );
+                    time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn read(&self, uuid: &Uuid) -> Result<Vec<u8>, Error> {
+        self.link().await?.read(uuid).await
+    }
+
+    /// Disconnect if a connection was opened, the error of the operation taking precedence
+    async fn close<T>(self, result: Result<T, Error>) -> Result<T, Error> {
+        let Some(link) = self.link.into_inner() else {
+            return result;
+        };
+
+        let closed = link.close().await;
+        result.and_then(|value| closed.map(|()| value))
+    }
+}
+
+/// Every Bluetooth adapter of the system, failing if there are none
+pub async fn get_centrals(manager: &Manager) -> Result<Vec<Adapter>, Error> {
+    let adapters = manager.adapters().await.map_err(Error::Btle)?;
+    if adapters.is_empty() {
+        return Err(Error::Message("No Bluetooth adapters found"));
+    }
+
+    Ok(adapters)
+}
+
+pub async fn get_central(manager: &Manager, index: usize) -> Result<Adapter, Error> {
+    get_centrals(manager)
+        .await?
+        .into_iter()
+        .nth(index)
+        .ok_or(Error::Message("Bluetooth adapter index out of range"))
+}
+
+/// Start scanning for the devices advertising a basestation service, or with `all` for every device
+pub async fn start_scan(adapter: &Adapter, all: bool) -> Result<(), Error> {
+    let filter = if all {
+        ScanFilter::default()
+    } else {
+        ScanFilter {
+            services: vec![V1_SERVICE_UUID, V2_SERVICE_UUID],
+        }
+    };
+
+    adapter.start_scan(filter).await.map_err(Error::Btle)
+}
+
+/// Stream the basestations as the adapter discovers them
+///
+/// The adapter must already be scanning. Peripherals it discovered before the call are not
+/// reported again.
+pub async fn discover(adapter: &Adapter) -> Result<impl Stream<Item = BaseStation>, Error> {
+    let events = adapter.events().await.map_err(Error::Btle)?;
+    let adapter = adapter.clone();
+
+    Ok(events.filter_map(move |event| {
+        let adapter = adapter.clone();
+        async move {
+            let CentralEvent::DeviceDiscovered(id) = event else {
+                return None;
+            };
+
+            let peripheral = adapter.peripheral(&id).await.ok()?;
+            let station = BaseStation::from_peripheral(&peripheral).await.ok()??;
+
+            debug!("Discovered {} ({id})", station.name);
+            Some(station)
+        }
+    }))
 }
 
 pub async fn write(
@@ -25,30 +506,1217 @@ pub async fn write(
     id: PeripheralId,
     data: &[u8],
     uuid: Uuid,
+    timeout: Duration,
 ) -> Result<(), Error> {
-    let peripheral = adapter.peripheral(&id).await.map_err(Error::Btle)?;
+    let connection = Connection::open(adapter, &id, timeout).await;
+    let result = match &connection {
+        Ok(connection) => connection.write(uuid, data).await,
+        Err(error) => Err(Error::Owned(error.to_string())),
+    };
+
+    if let Err(error) = &result {
+        warn!("Failed to write {uuid} on {id}: {error}");
+    }
+
+    match connection {
+        Ok(connection) => result.and(connection.disconnect().await),
+        Err(error) => Err(error),
+    }
+}
+
+pub async fn read(
+    adapter: &Adapter,
+    peripheral_id: &PeripheralId,
+    uuid: &Uuid,
+    timeout: Duration,
+) -> Result<Vec<u8>, Error> {
+    let connection = Connection::open(adapter, peripheral_id, timeout).await?;
+    let data = connection.read(uuid).await;
+    let disconnected = connection.disconnect().await;
+
+    data.and_then(|data| disconnected.map(|()| data))
+}
+
+/// Disconnect from the peripheral if it is still connected
+pub async fn disconnect(adapter: &Adapter, id: &PeripheralId) -> Result<(), Error> {
+    let peripheral = adapter.peripheral(id).await.map_err(Error::Btle)?;
+    if !peripheral.is_connected().await.map_err(Error::Btle)? {
+        return Ok(());
+    }
+
+    debug!("Disconnecting from {id}");
+    peripheral.disconnect().await.map_err(Error::Btle)
+}
+
+/// Whether the error means the peripheral is no longer connected
+///
+/// BlueZ reports this as a D-Bus error rather than [`btleplug::Error::NotConnected`].
+fn is_disconnected(error: &btleplug::Error) -> bool {
+    let message = match error {
+        btleplug::Error::NotConnected => return true,
+        btleplug::Error::RuntimeError(message) => message.to_lowercase(),
+        btleplug::Error::Other(error) => error.to_string().to_lowercase(),
+        _ => return false,
+    };
+
+    message.contains("not connected") || message.contains("disconnected")
+}
+
+/// A connection to a peripheral whose services were discovered once
+///
+/// Writes and reads over the same connection share the discovered characteristics instead of
+/// connecting and discovering them again each time.
+pub struct Connection {
+    peripheral: platform::Peripheral,
+    timeout:    Duration,
+    written:    AtomicBool,
+}
+
+impl Connection {
+    /// Connect to the peripheral and discover its services, each step taking at most `timeout`
+    pub async fn open(
+        adapter: &Adapter,
+        id: &PeripheralId,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let peripheral = adapter.peripheral(id).await.map_err(Error::Btle)?;
+        let connection = Self {
+            peripheral,
+            timeout,
+            written: AtomicBool::new(false),
+        };
 
-    if peripheral.connect().await.map_err(Error::Btle).is_ok()
-        && peripheral
-            .discover_services()
+        // A connection that timed out while discovering the services may still be up
+        if let Err(error) = connection.connect().await {
+            connection.peripheral.disconnect().await.ok();
+            return Err(error);
+        }
+
+        Ok(connection)
+    }
+
+    async fn connect(&self) -> Result<(), Error> {
+        let connected = async {
+            self.peripheral.connect().await?;
+            self.peripheral.discover_services().await
+        };
+
+        time::timeout(self.timeout, connected)
             .await
+            .map_err(|_| Error::Timeout {
+                id:        self.peripheral.id(),
+                operation: "connecting to",
+            })?
             .map_err(Error::Btle)
-            .is_ok()
-    {
-        if let Some(characteristic) = peripheral
+    }
+
+    fn characteristic(&self, uuid: &Uuid) -> Result<Characteristic, Error> {
+        self.peripheral
             .characteristics()
             .into_iter()
-            .find(|c| c.uuid == uuid)
-        {
-            let _ = peripheral
-                .write(&characteristic, data, WriteType::WithoutResponse)
-                .await
-                .map_err(Error::Btle);
+            .find(|c| c.uuid == *uuid)
+            .ok_or(Error::CharacteristicNotFound(*uuid))
+    }
+
+    /// Write to the characteristic, reconnecting once if the link dropped
+    ///
+    /// Fails without writing when the data is longer than [`MAX_WRITE_LENGTH`].
+    pub async fn write(&self, uuid: Uuid, data: &[u8]) -> Result<(), Error> {
+        check_length(data)?;
+        let characteristic = self.characteristic(&uuid)?;
+        debug!("Writing {data:02x?} to {uuid} on {}", self.peripheral.id());
+
+        // The link dropped between connecting and writing, other errors aren't worth retrying here
+        match self.write_characteristic(&characteristic, data).await {
+            Err(Error::Btle(error)) if is_disconnected(&error) => {
+                debug!(
+                    "Lost the connection to {}, reconnecting: {error}",
+                    self.peripheral.id()
+                );
+                self.connect().await?;
+                self.write_characteristic(&characteristic, data).await
+            }
+            result => result,
+        }?;
+
+        self.written.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn write_characteristic(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let properties = characteristic.properties;
+        let write_type = if properties.contains(CharPropFlags::WRITE_WITHOUT_RESPONSE) {
+            WriteType::WithoutResponse
+        } else if properties.contains(CharPropFlags::WRITE) {
+            WriteType::WithResponse
+        } else {
+            return Err(Error::Owned(format!(
+                "Characteristic {} is not writable",
+                characteristic.uuid
+            )));
+        };
+
+        let written = self.peripheral.write(characteristic, data, write_type);
+
+        time::timeout(self.timeout, written)
+            .await
+            .map_err(|_| Error::Timeout {
+                id:        self.peripheral.id(),
+                operation: "writing to",
+            })
+            .and_then(|result| result.map_err(Error::Btle))
+    }
+
+    /// Subscribe to the characteristic, streaming each value it notifies or indicates
+    ///
+    /// The stream doesn't end when the connection drops, check `is_connected` to notice.
+    pub async fn subscribe(&self, uuid: Uuid) -> Result<impl Stream<Item = Vec<u8>>, Error> {
+        let characteristic = self.characteristic(&uuid)?;
+        let pushes = CharPropFlags::NOTIFY | CharPropFlags::INDICATE;
+        if !characteristic.properties.intersects(pushes) {
+            return Err(Error::Owned(format!(
+                "Characteristic {uuid} doesn't notify or indicate"
+            )));
+        }
+
+        let notifications = self.peripheral.notifications().await.map_err(Error::Btle)?;
+        debug!("Subscribing to {uuid} on {}", self.peripheral.id());
+        time::timeout(self.timeout, self.peripheral.subscribe(&characteristic))
+            .await
+            .map_err(|_| Error::Timeout {
+                id:        self.peripheral.id(),
+                operation: "subscribing to",
+            })?
+            .map_err(Error::Btle)?;
+
+        Ok(notifications.filter_map(move |notification| {
+            std::future::ready((notification.uuid == uuid).then_some(notification.value))
+        }))
+    }
+
+    /// Whether the peripheral is still connected
+    pub async fn is_connected(&self) -> Result<bool, Error> {
+        self.peripheral.is_connected().await.map_err(Error::Btle)
+    }
+
+    /// The services discovered when connecting, with their characteristics
+    pub fn services(&self) -> BTreeSet<Service> {
+        self.peripheral.services()
+    }
+
+    /// Read the value of the characteristic
+    pub async fn read(&self, uuid: &Uuid) -> Result<Vec<u8>, Error> {
+        let characteristic = self.characteristic(uuid)?;
+        debug!("Reading {uuid} on {}", self.peripheral.id());
+
+        time::timeout(self.timeout, self.peripheral.read(&characteristic))
+            .await
+            .map_err(|_| Error::Timeout {
+                id:        self.peripheral.id(),
+                operation: "reading from",
+            })
+            .and_then(|result| result.map_err(Error::Btle))
+    }
+
+    /// Disconnect from the peripheral if it is still connected
+    ///
+    /// After a write this waits a second first, basestations ignore a write whose connection
+    /// drops right after it.
+    pub async fn disconnect(self) -> Result<(), Error> {
+        if self.written.load(Ordering::Relaxed) {
+            time::sleep(Duration::from_secs(1)).await;
+        }
+
+        if !self.peripheral.is_connected().await.map_err(Error::Btle)? {
+            return Ok(());
         }
+
+        debug!("Disconnecting from {}", self.peripheral.id());
+        self.peripheral.disconnect().await.map_err(Error::Btle)
+    }
+}
+
+/// Parse a Bluetooth address separated by colons or dashes
+pub fn parse_address(address: &str) -> Result<BDAddr, Error> {
+    let invalid = || Error::Owned(format!("Invalid Bluetooth address {address}"));
+    if !address.is_ascii() {
+        return Err(invalid());
+    }
+
+    BDAddr::from_str(&address.replace('-', ":")).map_err(|_| invalid())
+}
+
+/// V1: Parse an 8 character hex BSID into its four bytes, e.g. `AABBCCDD` or `AA:BB:CC:DD`
+pub fn parse_bsid(bsid: &str) -> Result<[u8; 4], Error> {
+    let hex = bsid
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-') && !c.is_whitespace())
+        .collect::<String>();
+
+    if hex.len() != 8 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::Owned(format!(
+            "V1: BSID {bsid} must be 8 hex characters, e.g. AABBCCDD or AA:BB:CC:DD"
+        )));
+    }
+
+    let aa = u8::from_str_radix(&hex[0..2], 16).map_err(Error::Std)?;
+    let bb = u8::from_str_radix(&hex[2..4], 16).map_err(Error::Std)?;
+    let cc = u8::from_str_radix(&hex[4..6], 16).map_err(Error::Std)?;
+    let dd = u8::from_str_radix(&hex[6..8], 16).map_err(Error::Std)?;
+
+    Ok([aa, bb, cc, dd])
+}
+
+/// Parse hex bytes, optionally separated by colons, dashes or spaces, e.g. `12:00:00:28`
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    let digits = hex
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-') && !c.is_whitespace())
+        .collect::<String>();
+
+    if digits.is_empty() || digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(Error::Owned(format!(
+            "{hex} must be an even number of hex characters"
+        )));
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(Error::Std))
+        .collect()
+}
+
+/// V1: The BSID as the last four bytes of the Bluetooth address
+///
+/// `AA:BB:11:22:33:44` gives the BSID `11223344`. This is a convention some units follow rather
+/// than a rule, the BSID printed on the back of the basestation is authoritative.
+pub fn bsid_from_address(address: BDAddr) -> [u8; 4] {
+    let [_, _, aa, bb, cc, dd] = address.into_inner();
+    [aa, bb, cc, dd]
+}
+
+/// Send the state command for the basestation's version
+pub async fn set_state(
+    adapter: &impl BleBackend,
+    station: &BaseStation,
+    state: State,
+    bsid: Option<&str>,
+    options: &Options,
+) -> Result<(), Error> {
+    let session = Session::new(adapter, &station.id, options);
+    let result = apply(&session, station, state, bsid).await;
+
+    session.close(result).await
+}
+
+/// Power the basestation off if it is on and on otherwise, returning the new state
+///
+/// V1 basestations can't report their state, so `assume` is used in its place.
+pub async fn toggle(
+    adapter: &impl BleBackend,
+    station: &BaseStation,
+    bsid: Option<&str>,
+    assume: Option<State>,
+    options: &Options,
+) -> Result<State, Error> {
+    let session = Session::new(adapter, &station.id, options);
+    let result = async {
+        let current = match station.version {
+            Version::V1 => assume.ok_or(Error::Message("V1: Power state can't be read"))?,
+            Version::V2 => read_state(&session).await?,
+        };
+
+        let state = match current {
+            State::On => State::Off,
+            State::Off | State::Standby => State::On,
+        };
+
+        apply(&session, station, state, bsid).await?;
+        Ok(state)
+    }
+    .await;
+
+    session.close(result).await
+}
+
+/// Power the basestation off, wait for `delay` and power it back on, over one connection
+pub async fn reboot(
+    adapter: &impl BleBackend,
+    station: &BaseStation,
+    bsid: Option<&str>,
+    delay: Duration,
+    options: &Options,
+) -> Result<(), Error> {
+    let session = Session::new(adapter, &station.id, options);
+    let result = async {
+        apply(&session, station, State::Off, bsid).await?;
+
+        debug!("Waiting {delay:?} before powering {} back on", station.name);
+        time::sleep(delay).await;
+
+        apply(&session, station, State::On, bsid).await
+    }
+    .await;
+
+    session.close(result).await
+}
+
+/// Send the state command over the session, failing before connecting if it can't be sent
+async fn apply<B: BleBackend>(
+    session: &Session<'_, B>,
+    station: &BaseStation,
+    state: State,
+    bsid: Option<&str>,
+) -> Result<(), Error> {
+    if !station.version.supports(state) {
+        return Err(Error::Owned(format!(
+            "{} basestations don't support {state}",
+            station.version
+        )));
+    }
+
+    info!("Setting {} to {state}", station.name);
+    match station.version {
+        Version::V1 => {
+            let Some(bsid) = bsid else {
+                return Err(Error::Message("V1: Basestation BSID is required"));
+            };
+
+            v1ctrl(session, state, bsid).await
+        }
+        Version::V2 => v2ctrl(session, station, state).await,
+    }
+}
+
+/// V1: Build the 20 byte power command for the BSID
+pub fn v1_command(bsid: [u8; 4], state: State) -> Result<Vec<u8>, Error> {
+    let [aa, bb, cc, dd] = bsid;
+
+    match state {
+        State::Off => Ok(vec![
+            0x12, 0x02, 0x00, 0x01, dd, cc, bb, aa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ]),
+        State::On => Ok(vec![
+            0x12, 0x00, 0x00, 0x00, dd, cc, bb, aa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+        ]),
+        State::Standby => Err(Error::Message(
+            "V1: Standby is not supported, only on and off",
+        )),
+    }
+}
+
+/// V2: Build the power command
+pub fn v2_command(state: State) -> Vec<u8> {
+    match state {
+        State::Off => vec![0x00],
+        State::On => vec![0x01],
+        State::Standby => vec![0x02],
     }
+}
+
+async fn v1ctrl<B: BleBackend>(
+    session: &Session<'_, B>,
+    state: State,
+    bsid: &str,
+) -> Result<(), Error> {
+    let cmd = v1_command(parse_bsid(bsid)?, state)?;
 
-    time::sleep(Duration::from_secs(1)).await;
-    peripheral.disconnect().await.map_err(Error::Btle)?;
+    repeat(session, &cmd, V1_UUID).await
+}
+
+async fn v2ctrl<B: BleBackend>(
+    session: &Session<'_, B>,
+    station: &BaseStation,
+    state: State,
+) -> Result<(), Error> {
+    repeat(session, &v2_command(state), V2_UUID).await?;
+
+    let options = session.options;
+    if options.confirm && !options.dry_run {
+        time::sleep(options.post_delay).await;
+        if read_state(session).await? != state {
+            return Err(Error::Message("V2: State did not change after writing"));
+        }
+
+        debug!("Confirmed {} is {state}", station.name);
+    }
 
     Ok(())
 }
+
+/// Send the command `options.repeat` times, succeeding if any of the writes did
+///
+/// Some basestations ignore a write now and then without reporting an error.
+async fn repeat<B: BleBackend>(
+    session: &Session<'_, B>,
+    cmd: &[u8],
+    uuid: Uuid,
+) -> Result<(), Error> {
+    let mut result = session.send(cmd, uuid).await;
+    for _ in 1..session.options.repeat {
+        time::sleep(Duration::from_millis(250)).await;
+        let repeated = session.send(cmd, uuid).await;
+        result = result.or(repeated);
+    }
+
+    result
+}
+
+/// V2: Read the current power state
+pub async fn get_state(
+    adapter: &impl BleBackend,
+    station: &BaseStation,
+    timeout: Duration,
+) -> Result<State, Error> {
+    station.require_v2()?;
+
+    let options = Options {
+        timeout,
+        ..Default::default()
+    };
+    let session = Session::new(adapter, &station.id, &options);
+    let result = read_state(&session).await;
+
+    session.close(result).await
+}
+
+async fn read_state<B: BleBackend>(session: &Session<'_, B>) -> Result<State, Error> {
+    parse_v2_state(&session.read(&V2_UUID).await?)
+}
+
+/// V2: Parse the value of the power state characteristic, as read or notified
+pub fn parse_v2_state(data: &[u8]) -> Result<State, Error> {
+    match data.first() {
+        Some(0x00) => Ok(State::Off),
+        Some(0x01) => Ok(State::On),
+        Some(0x02) => Ok(State::Standby),
+        Some(byte) => Err(Error::Owned(format!("V2: Unknown power state {byte:#04x}"))),
+        None => Err(Error::Message("V2: Unknown power state")),
+    }
+}
+
+/// V2: Read the firmware and hardware revisions and the serial number
+pub async fn get_device_info(
+    adapter: &impl BleBackend,
+    station: &BaseStation,
+    timeout: Duration,
+) -> Result<DeviceInfo, Error> {
+    station.require_v2()?;
+
+    let options = Options {
+        timeout,
+        ..Default::default()
+    };
+    let session = Session::new(adapter, &station.id, &options);
+    let result = read_device_info(&session).await;
+
+    session.close(result).await
+}
+
+async fn read_device_info<B: BleBackend>(session: &Session<'_, B>) -> Result<DeviceInfo, Error> {
+    Ok(DeviceInfo {
+        firmware: read_string(session, &FIRMWARE_REVISION_UUID).await?,
+        hardware: read_string(session, &HARDWARE_REVISION_UUID).await?,
+        serial:   read_string(session, &SERIAL_NUMBER_UUID).await?,
+    })
+}
+
+/// Read a string characteristic, `None` if the peripheral doesn't have it
+async fn read_string<B: BleBackend>(
+    session: &Session<'_, B>,
+    uuid: &Uuid,
+) -> Result<Option<String>, Error> {
+    match session.read(uuid).await {
+        Ok(data) => {
+            let text = String::from_utf8_lossy(&data);
+            Ok(Some(text.trim_end_matches('\0').trim().to_string()))
+        }
+        Err(Error::CharacteristicNotFound(_)) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// V2: Read the RF channel
+pub async fn get_channel(
+    adapter: &impl BleBackend,
+    station: &BaseStation,
+    timeout: Duration,
+) -> Result<u8, Error> {
+    station.require_v2()?;
+
+    let options = Options {
+        timeout,
+        ..Default::default()
+    };
+    let session = Session::new(adapter, &station.id, &options);
+    let result = read_channel(&session).await;
+
+    session.close(result).await
+}
+
+async fn read_channel<B: BleBackend>(session: &Session<'_, B>) -> Result<u8, Error> {
+    let data = session.read(&V2_CHANNEL_UUID).await?;
+    data.first()
+        .copied()
+        .ok_or(Error::Message("V2: Unknown channel"))
+}
+
+/// V2: The power state, RF channel and Device Information read by [`get_status`]
+#[derive(Debug)]
+pub struct Status {
+    pub state:   Result<State, Error>,
+    pub channel: Result<u8, Error>,
+    pub info:    Result<DeviceInfo, Error>,
+}
+
+/// V2: Read the power state, RF channel and Device Information over one connection
+///
+/// Fails only when the basestation can't be connected to, otherwise each read fails on its own.
+pub async fn get_status(
+    adapter: &impl BleBackend,
+    station: &BaseStation,
+    timeout: Duration,
+) -> Result<Status, Error> {
+    station.require_v2()?;
+
+    let options = Options {
+        timeout,
+        ..Default::default()
+    };
+    let session = Session::new(adapter, &station.id, &options);
+    session.link().await?;
+
+    let status = Status {
+        state:   read_state(&session).await,
+        channel: read_channel(&session).await,
+        info:    read_device_info(&session).await,
+    };
+
+    session.close(Ok(status)).await
+}
+
+/// V2: Set the RF channel, between 0 and 15
+pub async fn set_channel(
+    adapter: &impl BleBackend,
+    station: &BaseStation,
+    channel: u8,
+    options: &Options,
+) -> Result<(), Error> {
+    station.require_v2()?;
+    if channel > 15 {
+        return Err(Error::Owned(format!(
+            "V2: Channel must be between 0 and 15, not {channel}"
+        )));
+    }
+
+    let session = Session::new(adapter, &station.id, options);
+    let result = session.send(&[channel], V2_CHANNEL_UUID).await;
+
+    session.close(result).await
+}
+
+/// V2: Blink the LED so the basestation can be found
+pub async fn identify(
+    adapter: &impl BleBackend,
+    station: &BaseStation,
+    options: &Options,
+) -> Result<(), Error> {
+    station.require_v2()?;
+
+    let session = Session::new(adapter, &station.id, options);
+    let result = session.send(&[0x01], V2_IDENTIFY_UUID).await;
+
+    session.close(result).await
+}
+
+/// Client for the basestations near one Bluetooth adapter
+///
+/// Owns the manager and adapter so applications embedding this crate don't have to set them up
+/// or scan themselves. V1 basestations can only be controlled once a BSID was given.
+pub struct Lighthouse {
+    manager:   Manager,
+    adapter:   Adapter,
+    scan_time: Duration,
+    bsid:      Option<String>,
+    options:   Options,
+}
+
+impl Lighthouse {
+    /// Open the first Bluetooth adapter
+    pub async fn new() -> Result<Self, Error> {
+        Self::with_adapter(0).await
+    }
+
+    /// Open the Bluetooth adapter with this index
+    pub async fn with_adapter(index: usize) -> Result<Self, Error> {
+        let manager = Manager::new().await.map_err(Error::Btle)?;
+        let adapter = get_central(&manager, index).await?;
+
+        Ok(Self {
+            manager,
+            adapter,
+            scan_time: Duration::from_secs(10),
+            bsid: None,
+            options: Options::default(),
+        })
+    }
+
+    /// How long `list` scans for [default: 10s]
+    pub fn scan_time(mut self, scan_time: Duration) -> Self {
+        self.scan_time = scan_time;
+        self
+    }
+
+    /// V1: BSID to control V1 basestations with
+    pub fn bsid(mut self, bsid: impl Into<String>) -> Self {
+        self.bsid = Some(bsid.into());
+        self
+    }
+
+    /// Options for every write
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn manager(&self) -> &Manager {
+        &self.manager
+    }
+
+    pub fn adapter(&self) -> &Adapter {
+        &self.adapter
+    }
+
+    /// Every Bluetooth adapter of the system, the one of the client included
+    pub async fn adapters(&self) -> Result<Vec<Adapter>, Error> {
+        get_centrals(&self.manager).await
+    }
+
+    /// Scan for the nearby basestations
+    pub async fn list(&self) -> Result<Vec<BaseStation>, Error> {
+        start_scan(&self.adapter, false).await?;
+        time::sleep(self.scan_time).await;
+
+        let peripherals = self.adapter.peripherals().await.map_err(Error::Btle)?;
+        self.adapter.stop_scan().await.map_err(Error::Btle)?;
+
+        let mut stations = Vec::new();
+        for peripheral in peripherals.iter() {
+            if let Some(station) = BaseStation::from_peripheral(peripheral).await? {
+                stations.push(station);
+            }
+        }
+
+        Ok(stations)
+    }
+
+    /// Scan for the basestations, then set the state of each, reporting the progress to
+    /// `on_event`
+    ///
+    /// Fails with `NotFound` when no basestation was found and `Partial` when some failed.
+    pub async fn control(
+        &self,
+        state: State,
+        mut on_event: impl FnMut(DiscoveryEvent),
+    ) -> Result<(), Error> {
+        let discovered = discover(&self.adapter).await?;
+        start_scan(&self.adapter, false).await?;
+
+        let deadline = time::sleep(self.scan_time);
+        tokio::pin!(discovered, deadline);
+
+        // Basestations the adapter already knew about aren't discovered again
+        let mut stations: Vec<BaseStation> = Vec::new();
+        for peripheral in self.adapter.peripherals().await.map_err(Error::Btle)? {
+            if let Ok(Some(station)) = BaseStation::from_peripheral(&peripheral).await {
+                on_event(DiscoveryEvent::Discovered(station.clone()));
+                stations.push(station);
+            }
+        }
+
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                Some(station) = discovered.next() => {
+                    if stations.iter().any(|known| known.address == station.address) {
+                        continue;
+                    }
+
+                    on_event(DiscoveryEvent::Discovered(station.clone()));
+                    stations.push(station);
+                }
+            }
+        }
+        self.adapter.stop_scan().await.map_err(Error::Btle)?;
+
+        if stations.is_empty() {
+            return Err(Error::NotFound);
+        }
+
+        let mut failed = 0;
+        for station in stations.iter() {
+            let result = self.set_state(station, state).await;
+            if result.is_err() {
+                failed += 1;
+            }
+
+            on_event(DiscoveryEvent::Controlled(station.clone(), result));
+        }
+
+        if failed > 0 {
+            return Err(Error::Partial {
+                failed,
+                total: stations.len(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Power the basestation on or off, or V2: put it into standby
+    pub async fn set_state(&self, station: &BaseStation, state: State) -> Result<(), Error> {
+        set_state(
+            &self.adapter,
+            station,
+            state,
+            self.bsid.as_deref(),
+            &self.options,
+        )
+        .await
+    }
+
+    /// V2: Read the current power state
+    pub async fn status(&self, station: &BaseStation) -> Result<State, Error> {
+        get_state(&self.adapter, station, self.options.timeout).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicUsize, Arc, Mutex};
+
+    use super::*;
+
+    const BSID: [u8; 4] = [0xAA, 0xBB, 0xCC, 0xDD];
+
+    type Writes = Vec<(Uuid, Vec<u8>)>;
+
+    /// Records the writes and connections, and answers reads with a fixed value
+    #[derive(Default)]
+    struct MockBackend {
+        writes:  Arc<Mutex<Writes>>,
+        read:    Vec<u8>,
+        /// Characteristics the peripheral doesn't have
+        missing: Vec<Uuid>,
+        opened:  AtomicUsize,
+    }
+
+    impl MockBackend {
+        fn writes(&self) -> Writes {
+            self.writes.lock().unwrap().clone()
+        }
+
+        fn opened(&self) -> usize {
+            self.opened.load(Ordering::Relaxed)
+        }
+    }
+
+    struct MockLink {
+        writes:  Arc<Mutex<Writes>>,
+        read:    Vec<u8>,
+        missing: Vec<Uuid>,
+    }
+
+    impl BleBackend for MockBackend {
+        type Link = MockLink;
+
+        async fn open(&self, _id: &PeripheralId, _timeout: Duration) -> Result<MockLink, Error> {
+            self.opened.fetch_add(1, Ordering::Relaxed);
+            Ok(MockLink {
+                writes:  self.writes.clone(),
+                read:    self.read.clone(),
+                missing: self.missing.clone(),
+            })
+        }
+    }
+
+    impl BleLink for MockLink {
+        async fn write(&self, uuid: Uuid, data: &[u8]) -> Result<(), Error> {
+            self.writes.lock().unwrap().push((uuid, data.to_vec()));
+            Ok(())
+        }
+
+        async fn read(&self, uuid: &Uuid) -> Result<Vec<u8>, Error> {
+            if self.missing.contains(uuid) {
+                return Err(Error::CharacteristicNotFound(*uuid));
+            }
+
+            Ok(self.read.clone())
+        }
+
+        async fn close(self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn station(name: &str) -> BaseStation {
+        // BlueZ peripheral ids are D-Bus object paths
+        let id = r#"{"object_path":"/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF"}"#;
+
+        BaseStation {
+            name:    name.into(),
+            address: BDAddr::from([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]),
+            id:      serde_json::from_str(id).unwrap(),
+            version: classify(name).unwrap(),
+            rssi:    None,
+        }
+    }
+
+    #[test]
+    fn v1_command_on() {
+        let cmd = v1_command(BSID, State::On).unwrap();
+
+        assert_eq!(
+            cmd,
+            [
+                0x12, 0x00, 0x00, 0x00, 0xDD, 0xCC, 0xBB, 0xAA, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn v1_command_off() {
+        let cmd = v1_command(BSID, State::Off).unwrap();
+
+        assert_eq!(
+            cmd,
+            [
+                0x12, 0x02, 0x00, 0x01, 0xDD, 0xCC, 0xBB, 0xAA, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn v1_command_standby() {
+        assert!(v1_command(BSID, State::Standby).is_err());
+    }
+
+    #[test]
+    fn classify_by_prefix() {
+        assert_eq!(classify("HTC BS 1A2B3C4D"), Some(Version::V1));
+        assert_eq!(classify("HTC BS"), Some(Version::V1));
+        assert_eq!(classify("LHB-ABCD1234"), Some(Version::V2));
+        assert_eq!(classify("My LHB-ABCD1234"), None);
+        assert_eq!(classify("xHTC BS 1A2B"), None);
+        assert_eq!(classify("lhb-abcd1234"), None);
+        assert_eq!(classify(""), None);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        for state in [State::Off, State::On, State::Standby] {
+            assert_eq!(state.to_string().parse::<State>().unwrap(), state);
+        }
+
+        assert_eq!("StandBy".parse::<State>().unwrap(), State::Standby);
+        assert_eq!(
+            serde_json::to_string(&State::Standby).unwrap(),
+            r#""standby""#
+        );
+        assert_eq!(
+            serde_json::from_str::<Version>(r#""v2""#).unwrap(),
+            Version::V2
+        );
+        assert!("sleep".parse::<State>().is_err());
+    }
+
+    #[test]
+    fn v1_command_from_bsid() {
+        let bsid = parse_bsid("aabbccdd").unwrap();
+        let cmd = v1_command(bsid, State::On).unwrap();
+
+        assert_eq!(cmd.len(), 20);
+        assert_eq!(cmd[4..8], [0xDD, 0xCC, 0xBB, 0xAA]);
+    }
+
+    #[test]
+    fn classify_services_by_characteristic() {
+        let service = |uuid, characteristic| Service {
+            uuid,
+            primary: true,
+            characteristics: BTreeSet::from([Characteristic {
+                uuid:         characteristic,
+                service_uuid: uuid,
+                properties:   CharPropFlags::WRITE,
+                descriptors:  BTreeSet::new(),
+            }]),
+        };
+
+        let v1 = BTreeSet::from([service(V1_SERVICE_UUID, V1_UUID)]);
+        let v2 = BTreeSet::from([service(V2_SERVICE_UUID, V2_UUID)]);
+
+        assert_eq!(classify_services(&v1), Some(Version::V1));
+        assert_eq!(classify_services(&v2), Some(Version::V2));
+        assert_eq!(classify_services(&BTreeSet::new()), None);
+    }
+
+    #[test]
+    fn parse_v2_state_bytes() {
+        assert_eq!(parse_v2_state(&[0x00]).unwrap(), State::Off);
+        assert_eq!(parse_v2_state(&[0x01]).unwrap(), State::On);
+        assert_eq!(parse_v2_state(&[0x02]).unwrap(), State::Standby);
+        assert!(parse_v2_state(&[0x0B]).is_err());
+        assert!(parse_v2_state(&[]).is_err());
+    }
+
+    #[test]
+    fn parse_hex_separators() {
+        assert_eq!(parse_hex("12:00:0a").unwrap(), [0x12, 0x00, 0x0A]);
+        assert_eq!(parse_hex("12000A").unwrap(), [0x12, 0x00, 0x0A]);
+        assert!(parse_hex("120").is_err());
+        assert!(parse_hex("zz").is_err());
+    }
+
+    #[test]
+    fn bsid_from_address_takes_last_four_bytes() {
+        let address = BDAddr::from([0xAA, 0xBB, 0x11, 0x22, 0x33, 0x44]);
+
+        assert_eq!(bsid_from_address(address), [0x11, 0x22, 0x33, 0x44]);
+    }
+
+    #[test]
+    fn parse_bsid_separators() {
+        assert_eq!(parse_bsid("AA:BB:CC:DD").unwrap(), BSID);
+        assert_eq!(parse_bsid("aa-bb-cc-dd").unwrap(), BSID);
+        assert_eq!(parse_bsid(" AABB CCDD ").unwrap(), BSID);
+        assert!(parse_bsid("AA:BB:CC").is_err());
+        assert!(parse_bsid("AA:BB:CC:DG").is_err());
+    }
+
+    #[tokio::test]
+    async fn set_state_v1_writes_command() {
+        let backend = MockBackend::default();
+        let station = station("HTC BS CCDD");
+        let options = Options::default();
+
+        set_state(&backend, &station, State::On, Some("aabbccdd"), &options)
+            .await
+            .unwrap();
+
+        let cmd = v1_command(BSID, State::On).unwrap();
+        assert_eq!(backend.writes(), [(V1_UUID, cmd)]);
+    }
+
+    #[tokio::test]
+    async fn set_state_v1_requires_bsid() {
+        let backend = MockBackend::default();
+        let station = station("HTC BS CCDD");
+        let options = Options::default();
+
+        assert!(set_state(&backend, &station, State::On, None, &options)
+            .await
+            .is_err());
+        assert!(backend.writes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn set_state_v2_writes_command() {
+        let backend = MockBackend::default();
+        let station = station("LHB-1A2B3C4D");
+        let options = Options::default();
+
+        set_state(&backend, &station, State::Standby, None, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(backend.writes(), [(V2_UUID, vec![0x02])]);
+    }
+
+    #[tokio::test]
+    async fn set_state_dry_run_writes_nothing() {
+        let backend = MockBackend::default();
+        let station = station("LHB-1A2B3C4D");
+        let options = Options {
+            dry_run: true,
+            ..Default::default()
+        };
+
+        set_state(&backend, &station, State::On, None, &options)
+            .await
+            .unwrap();
+
+        assert!(backend.writes().is_empty());
+        assert_eq!(backend.opened(), 0);
+    }
+
+    #[tokio::test]
+    async fn get_status_reads_over_one_connection() {
+        let backend = MockBackend {
+            read: vec![0x01],
+            ..Default::default()
+        };
+        let station = station("LHB-1A2B3C4D");
+
+        let status = get_status(&backend, &station, Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(status.state.unwrap(), State::On);
+        assert_eq!(status.channel.unwrap(), 1);
+        assert_eq!(backend.opened(), 1);
+    }
+
+    #[tokio::test]
+    async fn set_state_confirms_over_the_same_connection() {
+        let backend = MockBackend {
+            read: vec![0x01],
+            ..Default::default()
+        };
+        let station = station("LHB-1A2B3C4D");
+        let options = Options {
+            confirm: true,
+            post_delay: Duration::ZERO,
+            ..Default::default()
+        };
+
+        set_state(&backend, &station, State::On, None, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(backend.writes(), [(V2_UUID, vec![0x01])]);
+        assert_eq!(backend.opened(), 1);
+    }
+
+    #[tokio::test]
+    async fn toggle_v2_writes_opposite_state() {
+        let backend = MockBackend {
+            read: vec![0x01],
+            ..Default::default()
+        };
+        let station = station("LHB-1A2B3C4D");
+        let options = Options::default();
+
+        let state = toggle(&backend, &station, None, None, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(state, State::Off);
+        assert_eq!(backend.writes(), [(V2_UUID, vec![0x00])]);
+        assert_eq!(backend.opened(), 1);
+    }
+
+    #[tokio::test]
+    async fn set_state_repeat_writes_every_time() {
+        let backend = MockBackend::default();
+        let station = station("LHB-1A2B3C4D");
+        let options = Options {
+            repeat: 2,
+            ..Default::default()
+        };
+
+        set_state(&backend, &station, State::On, None, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            backend.writes(),
+            [(V2_UUID, vec![0x01]), (V2_UUID, vec![0x01])]
+        );
+        assert_eq!(backend.opened(), 1);
+    }
+
+    #[tokio::test]
+    async fn set_state_rejects_unsupported_states_without_writing() {
+        let backend = MockBackend::default();
+        let station = station("HTC BS CCDD");
+        let options = Options::default();
+
+        let result = set_state(
+            &backend,
+            &station,
+            State::Standby,
+            Some("AABBCCDD"),
+            &options,
+        );
+
+        assert!(result.await.is_err());
+        assert!(backend.writes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_rejects_long_commands() {
+        let backend = MockBackend::default();
+        let station = station("LHB-1A2B3C4D");
+        let cmd = [0x00; MAX_WRITE_LENGTH + 1];
+
+        let options = Options::default();
+        let session = Session::new(&backend, &station.id, &options);
+
+        let result = session.send(&cmd, V2_UUID).await;
+
+        assert!(result.is_err());
+        assert!(backend.writes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reboot_v2_writes_off_then_on() {
+        let backend = MockBackend::default();
+        let station = station("LHB-1A2B3C4D");
+        let options = Options::default();
+
+        reboot(&backend, &station, None, Duration::ZERO, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            backend.writes(),
+            [(V2_UUID, vec![0x00]), (V2_UUID, vec![0x01])]
+        );
+        assert_eq!(backend.opened(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_device_info_trims_strings() {
+        let backend = MockBackend {
+            read: b"1.2.3\0".to_vec(),
+            ..Default::default()
+        };
+        let station = station("LHB-1A2B3C4D");
+
+        let info = get_device_info(&backend, &station, Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(info.firmware.as_deref(), Some("1.2.3"));
+        assert_eq!(
+            info.to_string(),
+            "firmware 1.2.3, hardware 1.2.3, serial 1.2.3"
+        );
+    }
+
+    #[tokio::test]
+    async fn get_device_info_skips_missing_characteristics() {
+        let backend = MockBackend {
+            read: b"1.2.3".to_vec(),
+            missing: vec![SERIAL_NUMBER_UUID],
+            ..Default::default()
+        };
+        let station = station("LHB-1A2B3C4D");
+
+        let info = get_device_info(&backend, &station, Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert_eq!(info.firmware.as_deref(), Some("1.2.3"));
+        assert_eq!(info.serial, None);
+    }
+}
@@ -1,118 +1,2841 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, OpenOptions},
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use btleplug::{
+    api::{
+        BDAddr,
+        Central,
+        CentralEvent,
+        CharPropFlags,
+        Manager as _,
+        Peripheral,
+        PeripheralProperties,
+        ScanFilter,
+    },
+    platform::{self, Adapter, Manager, PeripheralId},
+};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use clap_verbosity_flag::{Level, Verbosity, WarnLevel};
+use futures::{future, StreamExt};
+use lighthouse::{
+    BaseStation,
+    Connection,
+    DeviceInfo,
+    Error,
+    Lighthouse,
+    Options,
+    State,
+    Status,
+    Version,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    signal,
+    sync::Semaphore,
+    task::{self, JoinError, JoinSet},
+    time::{self, Instant},
+};
+use tracing::{debug, info, warn};
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*};
+
+use crate::{config::Config, sequence::Step};
+
+mod cache;
+mod config;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod sequence;
+
+/// Seconds --wait-for scans when no --timeout is given
+const WAIT_TIMEOUT: u64 = 300;
+
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Index of the Bluetooth adapter to use [default: 0]
+    #[arg(long, global = true)]
+    adapter: Option<usize>,
+
+    /// Control the basestations with every Bluetooth adapter at once
+    #[arg(long, global = true, conflicts_with = "adapter")]
+    all_adapters: bool,
+
+    /// Seconds to scan for basestations [default: 10]
+    #[arg(short, long, global = true)]
+    timeout: Option<u64>,
+
+    /// Only target basestations whose name contains this (repeatable)
+    #[arg(short, long, global = true)]
+    name: Vec<String>,
+
+    /// Only target basestations with this Bluetooth address (repeatable)
+    #[arg(short, long, global = true, value_parser = lighthouse::parse_address)]
+    mac: Vec<BDAddr>,
+
+    /// Only target the members of this group from the config (repeatable)
+    #[arg(short, long, global = true)]
+    group: Vec<String>,
+
+    /// Target every basestation found, the config's stations ignored
+    #[arg(long, global = true, conflicts_with_all = ["name", "mac", "group"])]
+    all: bool,
+
+    /// Names or addresses of the --group members
+    #[arg(skip)]
+    members: Vec<String>,
+
+    /// Basestations of the config, targeted when no --name, --mac, --group or --all is given
+    #[arg(skip)]
+    stations: Vec<config::Station>,
+
+    /// Only target V1 basestations
+    #[arg(long, global = true, conflicts_with = "only_v2")]
+    only_v1: bool,
+
+    /// Only target V2 basestations
+    #[arg(long, global = true)]
+    only_v2: bool,
+
+    /// Only target basestations with at least this signal strength in dBm, e.g. -70
+    #[arg(long, global = true, allow_negative_numbers = true)]
+    min_rssi: Option<i16>,
+
+    /// Connect to this known address without scanning first (repeatable)
+    #[arg(long, global = true, value_parser = lighthouse::parse_address)]
+    address: Vec<BDAddr>,
+
+    /// Connect to the basestations from the last scan without scanning first
+    #[arg(long, global = true)]
+    use_cache: bool,
+
+    /// Addresses of the last scan, connected to directly but not targeted like --address
+    #[arg(skip)]
+    cached: Vec<BDAddr>,
+
+    /// Connect to the basestations of an export file without scanning first
+    #[arg(long, global = true, value_name = "FILE")]
+    import: Option<PathBuf>,
+
+    /// Print the results as JSON, the same as --format json
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// How to print the results
+    #[arg(long, global = true, value_enum, default_value_t)]
+    format: Format,
+
+    /// Log the commands that would be sent without writing them
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Number of times to retry a failed write
+    #[arg(long, global = true, default_value_t = 2)]
+    retries: u32,
+
+    /// V2: Read the state back after writing to confirm it changed
+    #[arg(long, global = true)]
+    confirm: bool,
+
+    /// V2: Milliseconds to wait after writing before --confirm reads the state back
+    #[arg(long, global = true, value_name = "MS", default_value_t = 500)]
+    post_delay: u64,
+
+    /// Scan until this basestation is controlled, up to 300s or --timeout
+    #[arg(long, global = true, value_name = "NAME")]
+    wait_for: Option<String>,
+
+    /// Send each state command this many times, even when it succeeded
+    #[arg(long, global = true, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    repeat: u32,
+
+    /// Number of basestations expected to be found, failing if fewer respond
+    #[arg(long, global = true, value_name = "N")]
+    expect: Option<usize>,
+
+    /// Scan for the whole --timeout first, then control every basestation in address order
+    #[arg(long, global = true)]
+    settle_then_act: bool,
+
+    /// Scan SECS more after each basestation found, 3 if no SECS given, up to --max-timeout
+    #[arg(long, global = true, value_name = "SECS", num_args = 0..=1, default_missing_value = "3", conflicts_with = "settle_then_act")]
+    extend: Option<u64>,
+
+    /// Seconds --extend may keep scanning for in total
+    #[arg(long, global = true, value_name = "SECS", default_value_t = 60)]
+    max_timeout: u64,
+
+    /// Only target the basestation with the strongest signal
+    #[arg(long, global = true, conflicts_with = "extend")]
+    nearest: bool,
+
+    /// Restart the scan if no basestation was found after this many seconds
+    #[arg(long, global = true, value_name = "SECS")]
+    rescan_after: Option<u64>,
+
+    /// Number of times --rescan-after may restart the scan
+    #[arg(long, global = true, value_name = "N", default_value_t = 2)]
+    max_rescans: u32,
+
+    /// V1: Use the last four bytes of the Bluetooth address as the BSID
+    #[arg(long, global = true)]
+    bsid_from_mac: bool,
+
+    /// Rerun the scan up to N times, retrying only the basestations that haven't succeeded yet
+    #[arg(long, global = true, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    passes: u32,
+
+    /// Stop at the first basestation or sequence step that fails, skipping the rest
+    #[arg(long, global = true)]
+    fail_fast: bool,
+
+    /// Stop as soon as this many basestations have been controlled
+    #[arg(short, long, global = true)]
+    count: Option<usize>,
+
+    /// Scan for all devices, not only those advertising a basestation service
+    #[arg(long, global = true)]
+    scan_all: bool,
+
+    /// Refuse to control basestations unless --name, --mac, --group or --all is given
+    #[arg(long, global = true)]
+    require_target: bool,
+
+    /// Publish the state of each basestation to this MQTT broker, e.g. mqtt://localhost:1883
+    #[cfg(feature = "mqtt")]
+    #[arg(long, global = true)]
+    mqtt: Option<String>,
+
+    /// Seconds a connection or write may take before timing out
+    #[arg(long, global = true, value_name = "SECS", default_value_t = 5)]
+    connect_timeout: u64,
+
+    /// Milliseconds to wait after opening the Bluetooth adapter before scanning, for slow dongles
+    #[arg(long, global = true, value_name = "MS", default_value_t = 0)]
+    pre_scan_delay: u64,
+
+    /// Milliseconds to wait after discovering a basestation before connecting to it
+    #[arg(long, global = true, value_name = "MS", default_value_t = 0)]
+    settle: u64,
+
+    /// Number of basestations to connect to at the same time
+    #[arg(long, global = true, value_name = "N", default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent: u32,
+
+    /// Also write the logs to this file, every control action included
+    #[arg(long, global = true, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Don't color the output, also set by the NO_COLOR environment variable
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    #[command(flatten)]
+    verbose: Verbosity<WarnLevel>,
+}
+
+impl Args {
+    /// Fill in options missing from the command line with the config file
+    fn merge(&mut self, config: Config) -> Result<(), Error> {
+        // Waiting ignores the configured scan time, only --timeout bounds it
+        if self.wait_for.is_some() {
+            self.timeout = self.timeout.or(Some(WAIT_TIMEOUT));
+            self.count = self.count.or(Some(1));
+        }
+
+        let mut profile_bsid = None;
+        if let Command::Profile { name, state, .. } = &mut self.command {
+            let Some(profile) = config.profiles.get(name) else {
+                return Err(Error::Owned(format!(
+                    "Profile {name} not found in the config"
+                )));
+            };
+
+            *state = Some(profile.state);
+            profile_bsid.clone_from(&profile.bsid);
+            self.timeout = self.timeout.or(profile.timeout);
+            self.name.extend(profile.name.iter().cloned());
+            self.group.extend(profile.group.iter().cloned());
+            for mac in profile.mac.iter() {
+                self.mac.push(lighthouse::parse_address(mac)?);
+            }
+        }
+
+        self.adapter = self.adapter.or(config.adapter);
+        self.timeout = self.timeout.or(config.timeout);
+
+        if let Some(bsid) = self.bsid() {
+            bsid.bsid = bsid.bsid.take().or(profile_bsid).or(config.bsid);
+        }
+
+        if let Command::Sequence { steps, .. } = &mut self.command {
+            if steps.is_empty() {
+                for step in config.sequence {
+                    steps.push(Step::from_str(&step)?);
+                }
+            }
+        }
+
+        for group in self.group.iter() {
+            let Some(members) = config.groups.get(group) else {
+                return Err(Error::Owned(format!(
+                    "Group {group} not found in the config"
+                )));
+            };
+
+            self.members.extend(members.iter().cloned());
+        }
+
+        self.mac.extend(self.address.iter().copied());
+        let filtered = !self.name.is_empty() || !self.mac.is_empty() || !self.group.is_empty();
+        if !self.all && !filtered {
+            for station in config.stations.iter() {
+                if let Some(mac) = &station.mac {
+                    lighthouse::parse_address(mac)?;
+                }
+            }
+
+            self.stations = config.stations;
+        }
+
+        // Without any filter every basestation in range is controlled, a neighbor's included
+        self.require_target |= config.require_target;
+        let targeted = !self.name.is_empty() || !self.mac.is_empty() || !self.members.is_empty();
+        let targeted = targeted || !self.stations.is_empty();
+        let targeted = targeted || self.wait_for.is_some() || self.all;
+        if self.require_target && self.command.controls() && !targeted {
+            return Err(Error::Message(
+                "No target given, pass --name, --mac, --group or --all to control basestations",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the stream is a terminal that may be colored
+    fn color(&self, stream: &impl IsTerminal) -> bool {
+        let disabled = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        !self.no_color && !disabled && stream.is_terminal()
+    }
+
+    /// How long connecting to or writing to a basestation may take
+    fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout)
+    }
+
+    /// Longest controlling a single basestation may take, every retry included
+    fn control_timeout(&self) -> Duration {
+        // Connecting and writing may each take the connect timeout, the third leaves room for
+        // the pause before disconnecting, the retry backoff and --confirm, for every --repeat.
+        // Saturating, as huge values would otherwise overflow
+        let attempts = self
+            .connect_timeout()
+            .saturating_mul(3)
+            .saturating_mul(self.retries.saturating_add(1))
+            .saturating_mul(self.repeat);
+        if self.confirm {
+            attempts.saturating_add(Duration::from_millis(self.post_delay))
+        } else {
+            attempts
+        }
+    }
+
+    /// How basestations are written to, from --dry-run, --retries, --confirm and friends
+    fn options(&self) -> Options {
+        Options {
+            dry_run:    self.dry_run,
+            retries:    self.retries,
+            confirm:    self.confirm,
+            post_delay: Duration::from_millis(self.post_delay),
+            repeat:     self.repeat,
+            timeout:    self.connect_timeout(),
+        }
+    }
+
+    /// The BSID option of the subcommands that take one
+    fn bsid(&mut self) -> Option<&mut Bsid> {
+        match &mut self.command {
+            Command::On(bsid)
+            | Command::Off(bsid)
+            | Command::Toggle { bsid, .. }
+            | Command::Reboot { bsid, .. }
+            | Command::Profile { bsid, .. }
+            | Command::Watch { bsid, .. }
+            | Command::Sequence { bsid, .. } => Some(bsid),
+            #[cfg(feature = "http")]
+            Command::Serve { bsid, .. } => Some(bsid),
+            _ => None,
+        }
+    }
+
+    /// Whether results are printed as they come in, one line per basestation
+    fn plain(&self) -> bool {
+        self.format == Format::Plain
+    }
+
+    /// Whether --quiet was given, to print nothing but errors after controlling basestations
+    fn quiet(&self) -> bool {
+        self.verbose.log_level() < Some(Level::Warn)
+    }
+
+    /// Whether --verbose was given, to print more about each basestation
+    fn detailed(&self) -> bool {
+        self.verbose.log_level() > Some(Level::Warn)
+    }
+
+    /// When the scan ends, computed once when the scan starts
+    fn deadline(&self) -> Instant {
+        let timeout = self.timeout.unwrap_or(10);
+        debug!("Scanning for {timeout} seconds...");
+
+        Instant::now() + Duration::from_secs(timeout)
+    }
+
+    /// Whether the basestation passes the filters given on the command line
+    fn matches(&self, station: &BaseStation) -> bool {
+        let version = match station.version {
+            Version::V1 => !self.only_v2,
+            Version::V2 => !self.only_v1,
+        };
+        let name =
+            self.name.is_empty() || self.name.iter().any(|n| station.name.contains(n.as_str()));
+        let address = self.mac.is_empty() || self.mac.contains(&station.address);
+        let group = self.group.is_empty()
+            || self
+                .members
+                .iter()
+                .any(|member| identifies(station, member));
+        let wait_for = self
+            .wait_for
+            .as_deref()
+            .is_none_or(|wait_for| identifies(station, wait_for));
+        let rssi = self
+            .min_rssi
+            .is_none_or(|min| station.rssi.is_some_and(|rssi| rssi >= min));
+        let configured = config::targets(&self.stations, &station.name, station.address);
+
+        version && name && address && group && wait_for && rssi && configured
+    }
+
+    /// Each --name matching more than one of the found basestations, with the addresses it matched
+    fn ambiguous<'a>(&'a self, found: &[(&str, BDAddr)]) -> Vec<(&'a str, String)> {
+        let mut ambiguous = Vec::new();
+        for name in self.name.iter() {
+            let mut addresses = found
+                .iter()
+                .filter(|(station, _)| station.contains(name.as_str()))
+                .map(|(_, address)| *address)
+                .collect::<Vec<_>>();
+            addresses.sort();
+            addresses.dedup();
+
+            if addresses.len() > 1 {
+                let addresses = addresses.iter().map(BDAddr::to_string).collect::<Vec<_>>();
+                ambiguous.push((name.as_str(), addresses.join(", ")));
+            }
+        }
+
+        ambiguous
+    }
+}
+
+/// Whether the name or address refers to the basestation, names match when contained
+fn identifies(station: &BaseStation, target: &str) -> bool {
+    station.name.contains(target)
+        || lighthouse::parse_address(target).is_ok_and(|address| address == station.address)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum Format {
+    #[default]
+    Plain,
+    Table,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Power the basestations on
+    On(Bsid),
+
+    /// Power the basestations off
+    Off(Bsid),
+
+    /// V2: Put the basestations into standby
+    Standby,
+
+    /// Power each basestation off if it is on, and on otherwise
+    Toggle {
+        #[command(flatten)]
+        bsid: Bsid,
+
+        /// V1: State to assume the basestations are in, as it can't be read
+        #[arg(long)]
+        assume: Option<State>,
+    },
+
+    /// Power each basestation off and back on
+    Reboot {
+        #[command(flatten)]
+        bsid: Bsid,
+
+        /// Seconds to wait before powering the basestations back on
+        #[arg(long, value_name = "SECS", default_value_t = 2)]
+        delay: u64,
+    },
+
+    /// Run a profile from the config, e.g. `lighthouse profile evening`
+    Profile {
+        /// Name of the profile
+        name: String,
+
+        /// State of the profile
+        #[arg(skip)]
+        state: Option<State>,
+
+        #[command(flatten)]
+        bsid: Bsid,
+    },
+
+    /// Apply states to basestations in order, e.g. LHB-1A2B3C4D:on,2s,LHB-5E6F7A8B:on
+    Sequence {
+        /// Steps separated by commas, NAME:STATE or a delay like 2s or 500ms [default: config]
+        #[arg(value_delimiter = ',')]
+        steps: Vec<Step>,
+
+        #[command(flatten)]
+        bsid: Bsid,
+    },
+
+    /// Keep scanning and hold the basestations in a state, e.g. standby
+    Watch {
+        /// State to hold the basestations in [possible values: on, off, standby]
+        state: State,
+
+        #[command(flatten)]
+        bsid: Bsid,
+
+        /// Seconds between checks of each basestation
+        #[arg(long, value_name = "SECS", default_value_t = 60)]
+        poll_interval: u64,
+
+        /// V2: React to the state changes each basestation pushes instead of polling it
+        #[arg(long)]
+        notify: bool,
+
+        /// Serve Prometheus metrics on this address, e.g. 0.0.0.0:9090
+        #[cfg(feature = "metrics")]
+        #[arg(long, value_name = "ADDR")]
+        metrics_addr: Option<std::net::SocketAddr>,
+    },
+
+    /// Keep polling the power state and print only when it changes, e.g. to catch drops
+    Monitor {
+        /// Seconds between polls of each basestation
+        #[arg(long, value_name = "SECS", default_value_t = 30)]
+        interval: u64,
+    },
+
+    /// Serve a REST API to control the basestations over HTTP
+    #[cfg(feature = "http")]
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: std::net::SocketAddr,
+
+        #[command(flatten)]
+        bsid: Bsid,
+    },
+
+    /// List nearby basestations
+    List,
+
+    /// Report the current power state and channel of each basestation
+    Status,
+
+    /// Write the basestations found to a JSON or TOML file, by the extension of --output
+    Export {
+        /// File to write, printed as JSON when not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Print every Bluetooth device event as it arrives, basestation or not
+    Scan {
+        /// Seconds to scan for [default: --timeout]
+        #[arg(long, value_name = "SECS")]
+        duration: Option<u64>,
+    },
+
+    /// V2: Read or set the RF channel
+    #[command(subcommand)]
+    Channel(Channel),
+
+    /// V2: Blink the LED of each basestation
+    Identify,
+
+    /// Write raw bytes to any characteristic of the --mac basestations, for experimenting
+    Raw {
+        /// UUID of the characteristic to write
+        #[arg(long, value_name = "UUID")]
+        characteristic: uuid::Uuid,
+
+        /// Bytes to write as hex, e.g. 12:00:00:28
+        // The full path stops clap from taking the bytes as repeated values
+        #[arg(long, value_name = "HEX", value_parser = lighthouse::parse_hex)]
+        data: ::std::vec::Vec<u8>,
+    },
+
+    /// Print the bytes of any characteristic of the --mac basestations as hex and ASCII
+    RawRead {
+        /// UUID of the characteristic to read
+        #[arg(long, value_name = "UUID")]
+        characteristic: uuid::Uuid,
+    },
+
+    /// Print every service and characteristic of the --mac basestations, with their values
+    GattDump,
+
+    /// Print the version of the --mac or --name devices, both by their name and their services
+    Inspect,
+
+    /// List the available Bluetooth adapters
+    #[command(hide = true)]
+    ListAdapters,
+
+    /// Print the shell completion script
+    #[command(hide = true)]
+    Completions { shell: Shell },
+}
+
+impl Command {
+    /// Whether the subcommand changes the state, channel or characteristics of the basestations
+    fn controls(&self) -> bool {
+        matches!(
+            self,
+            Command::On(_)
+                | Command::Off(_)
+                | Command::Standby
+                | Command::Toggle { .. }
+                | Command::Reboot { .. }
+                | Command::Profile { .. }
+                | Command::Watch { .. }
+                | Command::Sequence { .. }
+                | Command::Channel(Channel::Set { .. })
+                | Command::Raw { .. }
+        )
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Channel {
+    /// Print the channel of each basestation
+    Get,
+
+    /// Set the channel of each basestation
+    Set {
+        /// Channel between 0 and 15
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=15))]
+        channel: u8,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct Bsid {
+    /// V1: Basestation BSID
+    #[arg(short, long)]
+    bsid: Option<String>,
+}
+
+/// A basestation as printed by --json
+#[derive(Debug, Serialize)]
+struct Report {
+    #[serde(skip)]
+    id:      PeripheralId,
+    name:    String,
+    address: BDAddr,
+    /// `None` for the devices of `lighthouse raw` that aren't named like a basestation
+    version: Option<Version>,
+    state:   Option<State>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rssi:    Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    info:    Option<DeviceInfo>,
+    /// Hex of the bytes read by `lighthouse raw-read`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data:    Option<String>,
+    result:  Option<Outcome>,
+}
+
+/// How controlling or reading a basestation went, `"ok"` or the error in the JSON
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Outcome {
+    Ok,
+    Failed(String),
+    /// Still being controlled when Ctrl-C was pressed
+    Interrupted,
+}
+
+impl Outcome {
+    fn is_ok(&self) -> bool {
+        *self == Self::Ok
+    }
+}
+
+impl From<Result<(), Error>> for Outcome {
+    fn from(result: Result<(), Error>) -> Self {
+        match result {
+            Ok(()) => Self::Ok,
+            Err(error) => Self::Failed(error.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::Failed(error) => write!(f, "{error}"),
+            Self::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl Serialize for Outcome {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Report {
+    fn new(station: &BaseStation) -> Self {
+        Self {
+            id:      station.id.clone(),
+            name:    station.name.clone(),
+            address: station.address,
+            version: Some(station.version),
+            state:   None,
+            channel: None,
+            rssi:    station.rssi,
+            info:    None,
+            data:    None,
+            result:  None,
+        }
+    }
+
+    /// A report on any device, whether or not it is named like a basestation
+    fn device(id: PeripheralId, properties: PeripheralProperties) -> Self {
+        let name = properties.local_name.unwrap_or_default();
+        Self {
+            id,
+            version: lighthouse::classify(&name),
+            name,
+            address: properties.address,
+            state: None,
+            channel: None,
+            rssi: properties.rssi,
+            info: None,
+            data: None,
+            result: None,
+        }
+    }
+}
+
+/// V1: Extract the last four BSID characters from the advertised name
+fn bsid_suffix(name: &str) -> Result<&str, Error> {
+    let id = name.trim_start_matches("HTC BS").trim();
+    if id.len() < 4 || !id.is_char_boundary(id.len() - 4) {
+        return Err(Error::Message("V1: Name is too short to contain a BSID"));
+    }
+
+    Ok(&id[(id.len() - 4)..])
+}
+
+/// The run failed for some of the basestations
+const EXIT_PARTIAL: u8 = 2;
+/// No basestations were found
+const EXIT_NOT_FOUND: u8 = 3;
+/// The config, cache or Bluetooth adapter could not be loaded
+const EXIT_INIT: u8 = 4;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut args = Args::parse();
+    if let Command::Completions { shell } = args.command {
+        let mut command = Args::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return ExitCode::SUCCESS;
+    }
+
+    let client = match init(&mut args).await {
+        Ok(init) => init,
+        Err(error) => {
+            eprintln!("Error: {error}");
+            return ExitCode::from(EXIT_INIT);
+        }
+    };
+
+    match run(&args, &client).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {error}");
+            match error {
+                Error::Partial { .. } => ExitCode::from(EXIT_PARTIAL),
+                Error::NotFound => ExitCode::from(EXIT_NOT_FOUND),
+                _ => ExitCode::FAILURE,
+            }
+        }
+    }
+}
+
+/// Load the config and cache, set up logging and open the Bluetooth adapter
+async fn init(args: &mut Args) -> Result<Lighthouse, Error> {
+    if args.json {
+        args.format = Format::Json;
+    }
+    args.json = args.format == Format::Json;
+
+    if args.use_cache {
+        args.cached = cache::load()?.iter().map(|device| device.address).collect();
+    }
+
+    if let Some(path) = &args.import {
+        for station in Export::load(path)?.stations {
+            args.address
+                .push(lighthouse::parse_address(&station.address)?);
+        }
+    }
+
+    args.merge(Config::load()?)?;
+
+    let level = args.verbose.log_level_filter().as_str();
+    let mut level = LevelFilter::from_str(level).unwrap_or(LevelFilter::WARN);
+    if args.dry_run {
+        level = level.max(LevelFilter::INFO);
+    }
+
+    let console = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(args.color(&std::io::stderr()))
+        .with_filter(level);
+
+    // Appended to rather than rotated, rotate it with logrotate's copytruncate
+    let file = match &args.log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let layer = fmt::layer().with_writer(Mutex::new(file)).with_ansi(false);
+            Some(layer.with_filter(level.max(LevelFilter::INFO)))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(console)
+        .with(file)
+        .init();
+
+    let client = Lighthouse::with_adapter(args.adapter.unwrap_or(0)).await?;
+
+    // Some USB adapters find nothing when scanning right after they were opened
+    if args.pre_scan_delay > 0 {
+        debug!(
+            "Waiting {}ms for the adapter to warm up",
+            args.pre_scan_delay
+        );
+        time::sleep(Duration::from_millis(args.pre_scan_delay)).await;
+    }
+
+    Ok(client)
+}
+
+/// Run the subcommand
+async fn run(args: &Args, client: &Lighthouse) -> Result<(), Error> {
+    let adapter = client.adapter();
+    match &args.command {
+        Command::On(Bsid { bsid }) => control(client, args, State::On, bsid.as_deref()).await,
+        Command::Off(Bsid { bsid }) => control(client, args, State::Off, bsid.as_deref()).await,
+        Command::Standby => control(client, args, State::Standby, None).await,
+        Command::Toggle {
+            bsid: Bsid { bsid },
+            assume,
+        } => toggle(adapter, args, bsid.as_deref(), *assume).await,
+        Command::Reboot {
+            bsid: Bsid { bsid },
+            delay,
+        } => reboot(adapter, args, bsid.as_deref(), Duration::from_secs(*delay)).await,
+        Command::Profile {
+            state,
+            bsid: Bsid { bsid },
+            ..
+        } => {
+            let state = state.ok_or(Error::Message("Profile has no state"))?;
+            control(client, args, state, bsid.as_deref()).await
+        }
+        Command::Sequence {
+            steps,
+            bsid: Bsid { bsid },
+        } => sequence(adapter, args, steps, bsid.as_deref()).await,
+        Command::Watch {
+            state,
+            bsid: Bsid { bsid },
+            poll_interval,
+            notify,
+            ..
+        } => {
+            let interval = Duration::from_secs(*poll_interval);
+            watch(adapter, args, *state, bsid.as_deref(), interval, *notify).await
+        }
+        Command::Monitor { interval } => {
+            monitor(adapter, args, Duration::from_secs(*interval)).await
+        }
+        #[cfg(feature = "http")]
+        Command::Serve {
+            listen,
+            bsid: Bsid { bsid },
+        } => {
+            if let Some(bsid) = bsid {
+                lighthouse::parse_bsid(bsid)?;
+            }
+
+            let options = args.options();
+
+            start_scan(adapter, args).await?;
+            http::serve(adapter.clone(), *listen, bsid.clone(), options).await
+        }
+        Command::List => list(adapter, args).await,
+        Command::Status => status(adapter, args).await,
+        Command::Export { output } => export(adapter, args, output.as_deref()).await,
+        Command::Scan { duration } => scan_events(adapter, args, *duration).await,
+        Command::Channel(command) => channel(adapter, args, command).await,
+        Command::Identify => identify(adapter, args).await,
+        Command::Raw {
+            characteristic,
+            data,
+        } => raw(adapter, args, *characteristic, data).await,
+        Command::RawRead { characteristic } => raw_read(adapter, args, characteristic).await,
+        Command::GattDump => gatt_dump(adapter, args).await,
+        Command::Inspect => inspect(adapter, args).await,
+        Command::ListAdapters => list_adapters(client.manager()).await,
+        Command::Completions { .. } => unreachable!("Completions are printed before connecting"),
+    }
+}
+
+/// Print the index and info of each Bluetooth adapter
+async fn list_adapters(manager: &Manager) -> Result<(), Error> {
+    let adapters = manager.adapters().await.map_err(Error::Btle)?;
+    for (index, adapter) in adapters.iter().enumerate() {
+        let info = adapter.adapter_info().await.map_err(Error::Btle)?;
+        println!("{index}: {info}");
+    }
+
+    Ok(())
+}
+
+/// Print the discovery events of all devices until the duration elapsed or Ctrl-C
+async fn scan_events(adapter: &Adapter, args: &Args, duration: Option<u64>) -> Result<(), Error> {
+    let mut events = adapter.events().await.map_err(Error::Btle)?;
+    adapter
+        .start_scan(ScanFilter::default())
+        .await
+        .map_err(Error::Btle)?;
+
+    let start = Instant::now();
+    let deadline = match duration {
+        Some(duration) => start + Duration::from_secs(duration),
+        None => args.deadline(),
+    };
+    let deadline = time::sleep_until(deadline);
+    let interrupt = signal::ctrl_c();
+    tokio::pin!(deadline, interrupt);
+
+    loop {
+        let event = tokio::select! {
+            () = &mut deadline => break,
+            result = &mut interrupt => {
+                result.map_err(Error::Io)?;
+                break;
+            }
+            event = events.next() => match event {
+                Some(event) => event,
+                None => break,
+            },
+        };
+
+        let (kind, id) = match event {
+            CentralEvent::DeviceDiscovered(id) => ("discovered", id),
+            CentralEvent::DeviceUpdated(id) => ("updated", id),
+            CentralEvent::DeviceDisconnected(id) => ("disconnected", id),
+            _ => continue,
+        };
+
+        // Devices that went away may not have properties anymore
+        let properties = match adapter.peripheral(&id).await {
+            Ok(peripheral) => peripheral.properties().await.ok().flatten(),
+            Err(_) => None,
+        };
+        let name = properties
+            .as_ref()
+            .and_then(|properties| properties.local_name.as_deref())
+            .unwrap_or("-");
+        let rssi = properties
+            .as_ref()
+            .and_then(|properties| properties.rssi)
+            .map_or("-".into(), |rssi| format!("{rssi} dBm"));
+
+        let elapsed = start.elapsed().as_secs_f32();
+        println!("{elapsed:7.3}s {kind:12} {id} {name} {rssi}");
+    }
+
+    adapter.stop_scan().await.map_err(Error::Btle)
+}
+
+/// Start scanning on the adapter, only for basestations unless --scan-all is set
+async fn start_scan(adapter: &Adapter, args: &Args) -> Result<(), Error> {
+    let info = adapter.adapter_info().await.map_err(Error::Btle)?;
+    info!("Starting scan on {info}...");
+
+    lighthouse::start_scan(adapter, args.scan_all).await
+}
+
+/// Restart the scan, the first one of some adapters finds nothing
+async fn rescan(adapter: &Adapter, args: &Args) -> Result<(), Error> {
+    info!("Nothing found yet, restarting the scan...");
+    if let Err(error) = adapter.stop_scan().await {
+        debug!("Failed to stop the scan: {error}");
+    }
+
+    start_scan(adapter, args).await
+}
+
+/// Scan for nearby peripherals
+async fn scan(adapter: &Adapter, args: &Args) -> Result<Vec<platform::Peripheral>, Error> {
+    start_scan(adapter, args).await?;
+
+    let deadline = args.deadline();
+    if let Some(after) = args.rescan_after {
+        for _ in 0..args.max_rescans {
+            let window = Instant::now() + Duration::from_secs(after);
+            if window >= deadline {
+                break;
+            }
+
+            time::sleep_until(window).await;
+            if !adapter.peripherals().await.map_err(Error::Btle)?.is_empty() {
+                break;
+            }
+
+            rescan(adapter, args).await?;
+        }
+    }
+
+    time::sleep_until(deadline).await;
+
+    let peripherals = adapter.peripherals().await.map_err(Error::Btle)?;
+    if peripherals.is_empty() {
+        return Err(Error::NotFound);
+    }
+
+    for peripheral in peripherals.iter() {
+        debug!("Discovered {}", peripheral.id());
+    }
+
+    Ok(peripherals)
+}
+
+/// Scan for nearby basestations passing the filters
+async fn stations(adapter: &Adapter, args: &Args) -> Result<Vec<BaseStation>, Error> {
+    let mut stations = Vec::new();
+    for peripheral in scan(adapter, args).await?.iter() {
+        if let Some(station) = BaseStation::from_peripheral(peripheral).await? {
+            if args.matches(&station) {
+                stations.push(station);
+            }
+        }
+    }
+
+    if args.nearest {
+        stations = nearest(stations).into_iter().collect();
+    }
+
+    if stations.is_empty() {
+        return Err(Error::NotFound);
+    }
+
+    // Discovery order changes from run to run
+    stations.sort_by(|a, b| a.address.cmp(&b.address).then_with(|| a.name.cmp(&b.name)));
+
+    Ok(stations)
+}
+
+/// The basestation with the strongest signal, those without a signal strength can't be compared
+fn nearest(stations: Vec<BaseStation>) -> Option<BaseStation> {
+    stations
+        .into_iter()
+        .filter(|station| station.rssi.is_some())
+        .max_by_key(|station| station.rssi)
+}
+
+/// Print the id, address, version and signal strength of each basestation
+async fn list(adapter: &Adapter, args: &Args) -> Result<(), Error> {
+    let mut reports = Vec::new();
+    for station in stations(adapter, args).await? {
+        if args.plain() {
+            let BaseStation {
+                name,
+                address,
+                id,
+                version,
+                rssi,
+            } = &station;
+            match rssi {
+                Some(rssi) => println!("{name}: {version} {address} {rssi} dBm ({id})"),
+                None => println!("{name}: {version} {address} ({id})"),
+            }
+        }
+
+        let mut report = Report::new(&station);
+        if args.detailed() && station.version == Version::V2 {
+            report.info = device_info(adapter, args, &station).await;
+            report.channel = read_channel(adapter, args, &station).await;
+            if let (true, Some(channel)) = (args.plain(), report.channel) {
+                println!("  channel {channel}");
+            }
+        }
+
+        reports.push(report);
+    }
+
+    warn_shared_channels(&reports);
+    finish(args, reports).await
+}
+
+/// The basestations as written by `lighthouse export` and read by --import
+#[derive(Debug, Deserialize, Serialize)]
+struct Export {
+    stations: Vec<Exported>,
+}
+
+impl Export {
+    /// Read an export, TOML if the extension says so and JSON otherwise
+    fn load(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(Error::Io)?;
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&text).map_err(Error::TomlParse)
+        } else {
+            serde_json::from_str(&text).map_err(Error::Json)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Exported {
+    name:    String,
+    address: String,
+    version: Version,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rssi:    Option<i16>,
+}
+
+/// Write the name, address, version, V2: channel and signal strength of each basestation
+async fn export(adapter: &Adapter, args: &Args, output: Option<&Path>) -> Result<(), Error> {
+    let mut exported = Vec::new();
+    for station in stations(adapter, args).await? {
+        let channel = match station.version {
+            Version::V1 => None,
+            Version::V2 => read_channel(adapter, args, &station).await,
+        };
+
+        exported.push(Exported {
+            name: station.name,
+            address: station.address.to_string(),
+            version: station.version,
+            channel,
+            rssi: station.rssi,
+        });
+    }
+
+    let export = Export { stations: exported };
+    let toml = output.is_some_and(|path| path.extension().is_some_and(|ext| ext == "toml"));
+    let text = if toml {
+        toml::to_string_pretty(&export).map_err(Error::TomlSerialize)?
+    } else {
+        serde_json::to_string_pretty(&export).map_err(Error::Json)?
+    };
+
+    match output {
+        Some(path) => {
+            fs::write(path, text).map_err(Error::Io)?;
+            info!(
+                "Wrote {} basestations to {}",
+                export.stations.len(),
+                path.display()
+            );
+            Ok(())
+        }
+        None => {
+            println!("{text}");
+            Ok(())
+        }
+    }
+}
+
+/// Print the power state and V2: channel of each basestation
+async fn status(adapter: &Adapter, args: &Args) -> Result<(), Error> {
+    let mut reports = Vec::new();
+    for station in stations(adapter, args).await? {
+        let mut report = Report::new(&station);
+        if station.version == Version::V1 {
+            if args.plain() {
+                println!("{} {}: unknown (V1)", report.name, report.address);
+            }
+        } else {
+            // Recorded for every format, so the exit code doesn't depend on it
+            match lighthouse::get_status(adapter, &station, args.connect_timeout()).await {
+                Ok(Status {
+                    state,
+                    channel,
+                    info,
+                }) => {
+                    report.channel = known_channel(&station, channel);
+                    let channel = report
+                        .channel
+                        .map_or(String::new(), |channel| format!(", channel {channel}"));
+
+                    // Kept for every format too, it is what --mqtt publishes
+                    if let Ok(state) = &state {
+                        if args.plain() {
+                            println!("{} {}: {state}{channel}", report.name, report.address);
+                        }
+
+                        report.state = Some(*state);
+                    }
+
+                    report.result = Some(state.map(|_| ()).into());
+                    report.info = shown_info(args, &station, info);
+                }
+                Err(error) => report.result = Some(Err(error).into()),
+            }
+        }
+
+        reports.push(report);
+    }
+
+    warn_shared_channels(&reports);
+    finish(args, reports).await
+}
+
+/// V2: Read the RF channel of the basestation, `None` if it can't be read
+async fn read_channel(adapter: &Adapter, args: &Args, station: &BaseStation) -> Option<u8> {
+    let channel = lighthouse::get_channel(adapter, station, args.connect_timeout()).await;
+    known_channel(station, channel)
+}
+
+/// The channel if it was read, warning why it wasn't otherwise
+fn known_channel(station: &BaseStation, channel: Result<u8, Error>) -> Option<u8> {
+    match channel {
+        Ok(channel) => Some(channel),
+        Err(error) => {
+            warn!("{}: Failed to read the channel: {error}", station.name);
+            None
+        }
+    }
+}
+
+/// V2: Warn about basestations on the same channel, which interfere with each other
+fn warn_shared_channels(reports: &[Report]) {
+    let mut channels = HashMap::<u8, Vec<&str>>::new();
+    for report in reports.iter() {
+        if let Some(channel) = report.channel {
+            channels.entry(channel).or_default().push(&report.name);
+        }
+    }
+
+    for (channel, names) in channels.iter() {
+        if names.len() > 1 {
+            warn!("{} share channel {channel}", names.join(", "));
+        }
+    }
+}
+
+/// V2: Read the Device Information of the basestation, printing it with --format plain
+async fn device_info(adapter: &Adapter, args: &Args, station: &BaseStation) -> Option<DeviceInfo> {
+    let info = lighthouse::get_device_info(adapter, station, args.connect_timeout()).await;
+    shown_info(args, station, info)
+}
+
+/// The Device Information if it was read, printed with --format plain, warning why it wasn't
+/// otherwise
+fn shown_info(
+    args: &Args,
+    station: &BaseStation,
+    info: Result<DeviceInfo, Error>,
+) -> Option<DeviceInfo> {
+    match info {
+        Ok(info) => {
+            if args.plain() && info != DeviceInfo::default() {
+                println!("  {info}");
+            }
+
+            Some(info)
+        }
+        Err(error) => {
+            warn!(
+                "{}: Failed to read the device information: {error}",
+                station.name
+            );
+            None
+        }
+    }
+}
+
+/// Flip the power state of each basestation
+async fn toggle(
+    adapter: &Adapter,
+    args: &Args,
+    bsid: Option<&str>,
+    assume: Option<State>,
+) -> Result<(), Error> {
+    if let Some(bsid) = bsid {
+        lighthouse::parse_bsid(bsid)?;
+    }
+
+    let options = args.options();
+
+    let mut reports = Vec::new();
+    for station in stations(adapter, args).await? {
+        if !bsid_matches(args, &station, bsid) {
+            continue;
+        }
+
+        let mut report = Report::new(&station);
+        let bsid = station_bsid(args, &station, bsid);
+        let result = lighthouse::toggle(adapter, &station, bsid.as_deref(), assume, &options);
+        let result = result.await;
+        let result = result.map(|state| report.state = Some(state));
+        reports.push(handle(report, result));
+        if fails_fast(args, &reports) {
+            break;
+        }
+    }
+
+    finish(args, reports).await
+}
+
+/// Power cycle each basestation
+async fn reboot(
+    adapter: &Adapter,
+    args: &Args,
+    bsid: Option<&str>,
+    delay: Duration,
+) -> Result<(), Error> {
+    if let Some(bsid) = bsid {
+        lighthouse::parse_bsid(bsid)?;
+    }
+
+    let options = args.options();
+
+    let mut reports = Vec::new();
+    for station in stations(adapter, args).await? {
+        if !bsid_matches(args, &station, bsid) {
+            continue;
+        }
+
+        let mut report = Report::new(&station);
+        let bsid = station_bsid(args, &station, bsid);
+        let result = lighthouse::reboot(adapter, &station, bsid.as_deref(), delay, &options);
+        let result = result.await;
+        let result = result.map(|()| report.state = Some(State::On));
+        reports.push(handle(report, result));
+        if fails_fast(args, &reports) {
+            break;
+        }
+    }
+
+    finish(args, reports).await
+}
+
+/// V2: Print or set the channel of each basestation
+async fn channel(adapter: &Adapter, args: &Args, command: &Channel) -> Result<(), Error> {
+    // Channel and identify writes are sent once, --confirm and --repeat are for power states
+    let options = args.options();
+
+    let stations = stations(adapter, args).await?;
+    if let Channel::Set { .. } = command {
+        // Basestations sharing a channel interfere, so a channel is only set on the one meant
+        let found = stations
+            .iter()
+            .filter(|station| station.version == Version::V2)
+            .map(|station| (station.name.as_str(), station.address))
+            .collect::<Vec<_>>();
+        if let Some((name, addresses)) = args.ambiguous(&found).into_iter().next() {
+            return Err(Error::Owned(format!(
+                "--name {name} matches {addresses}, pick one with --mac"
+            )));
+        }
+    }
+
+    let mut reports = Vec::new();
+    for station in stations {
+        if station.version != Version::V2 {
+            continue;
+        }
+
+        let mut report = Report::new(&station);
+        match command {
+            Channel::Get => {
+                let result = lighthouse::get_channel(adapter, &station, args.connect_timeout());
+                let result = result.await;
+                if let Ok(channel) = &result {
+                    if args.plain() {
+                        println!("{} {}: channel {channel}", report.name, report.address);
+                    }
+
+                    report.channel = Some(*channel);
+                }
+
+                report.result = Some(result.map(|_| ()).into());
+            }
+            Channel::Set { channel } => {
+                let result = lighthouse::set_channel(adapter, &station, *channel, &options).await;
+                report.channel = Some(*channel);
+                report = handle(report, result);
+            }
+        }
+
+        reports.push(report);
+    }
+
+    finish(args, reports).await
+}
+
+/// V2: Blink the LED of each basestation
+async fn identify(adapter: &Adapter, args: &Args) -> Result<(), Error> {
+    // Channel and identify writes are sent once, --confirm and --repeat are for power states
+    let options = args.options();
+
+    let mut reports = Vec::new();
+    for station in stations(adapter, args).await? {
+        if station.version != Version::V2 {
+            continue;
+        }
+
+        info!("Identifying {}", station.name);
+        let result = lighthouse::identify(adapter, &station, &options).await;
+        reports.push(handle(Report::new(&station), result));
+    }
+
+    finish(args, reports).await
+}
+
+/// Write the bytes to the characteristic of each --mac basestation
+async fn raw(adapter: &Adapter, args: &Args, uuid: uuid::Uuid, data: &[u8]) -> Result<(), Error> {
+    if args.mac.is_empty() {
+        return Err(Error::Message("raw needs --mac to pick the basestations"));
+    }
+
+    let mut reports = Vec::new();
+    for report in addressed(adapter, args).await? {
+        let result = if args.dry_run {
+            info!("Would write {data:02X?} to {uuid} on {}", report.name);
+            Ok(())
+        } else {
+            let id = report.id.clone();
+            lighthouse::write(adapter, id, data, uuid, args.connect_timeout()).await
+        };
+
+        reports.push(handle(report, result));
+    }
+
+    finish(args, reports).await
+}
+
+/// Read the characteristic of each --mac basestation, printing it with --format plain
+async fn raw_read(adapter: &Adapter, args: &Args, uuid: &uuid::Uuid) -> Result<(), Error> {
+    if args.mac.is_empty() {
+        return Err(Error::Message(
+            "raw-read needs --mac to pick the basestations",
+        ));
+    }
+
+    let mut reports = Vec::new();
+    for mut report in addressed(adapter, args).await? {
+        match lighthouse::read(adapter, &report.id, uuid, args.connect_timeout()).await {
+            Ok(data) => {
+                if args.plain() {
+                    let (hex, ascii) = (hex(&data), ascii(&data));
+                    println!("{} {}: {hex}  {ascii}", report.name, report.address);
+                }
+
+                report.data = Some(hex(&data));
+                report.result = Some(Outcome::Ok);
+            }
+            Err(error) => report = handle(report, Err(error)),
+        }
+
+        reports.push(report);
+    }
+
+    finish(args, reports).await
+}
+
+/// Print the GATT table of each --mac basestation, reading every readable characteristic
+async fn gatt_dump(adapter: &Adapter, args: &Args) -> Result<(), Error> {
+    if args.mac.is_empty() {
+        return Err(Error::Message(
+            "gatt-dump needs --mac to pick the basestations",
+        ));
+    }
+
+    let mut failed = 0;
+    let devices = addressed(adapter, args).await?;
+    for device in devices.iter() {
+        println!("{} {}", device.name, device.address);
+
+        let connection = match Connection::open(adapter, &device.id, args.connect_timeout()).await {
+            Ok(connection) => connection,
+            Err(error) => {
+                println!("  {error}");
+                failed += 1;
+                continue;
+            }
+        };
+
+        for service in connection.services() {
+            println!("  Service {}", service.uuid);
+            for characteristic in service.characteristics.iter() {
+                let uuid = characteristic.uuid;
+                let properties = characteristic.properties;
+                if !properties.contains(CharPropFlags::READ) {
+                    println!("    {uuid} [{properties:?}]");
+                    continue;
+                }
+
+                match connection.read(&uuid).await {
+                    Ok(data) => {
+                        let (hex, ascii) = (hex(&data), ascii(&data));
+                        println!("    {uuid} [{properties:?}] {hex}  {ascii}");
+                    }
+                    Err(error) => println!("    {uuid} [{properties:?}] {error}"),
+                }
+            }
+        }
+
+        if let Err(error) = connection.disconnect().await {
+            warn!("{}: {error}", device.name);
+        }
+    }
+
+    if failed > 0 {
+        return Err(Error::Partial {
+            failed,
+            total: devices.len(),
+        });
+    }
+
+    Ok(())
+}
+
+/// The --mac devices found by the scan, including those that aren't named like a basestation
+///
+/// Left out by [`stations`], but worth poking at with the raw commands all the same.
+async fn addressed(adapter: &Adapter, args: &Args) -> Result<Vec<Report>, Error> {
+    let mut reports = Vec::new();
+    for peripheral in scan(adapter, args).await? {
+        let Some(properties) = peripheral.properties().await.map_err(Error::Btle)? else {
+            continue;
+        };
+
+        if args.mac.contains(&properties.address) {
+            reports.push(Report::device(peripheral.id(), properties));
+        }
+    }
+
+    Ok(reports)
+}
+
+/// Version of a device by its name and by its services, as printed by `lighthouse inspect`
+#[derive(Debug, Serialize)]
+struct Inspection {
+    name:        String,
+    address:     BDAddr,
+    by_name:     Option<Version>,
+    by_services: Option<Version>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result:      Option<String>,
+}
+
+/// Classify each --mac or --name device by its name, then connect to cross-check its services
+///
+/// Unlike the other commands this doesn't skip devices whose name isn't a basestation's.
+async fn inspect(adapter: &Adapter, args: &Args) -> Result<(), Error> {
+    if args.mac.is_empty() && args.name.is_empty() {
+        return Err(Error::Message(
+            "inspect needs --mac or --name to pick the devices",
+        ));
+    }
+
+    let mut inspections = Vec::new();
+    for peripheral in scan(adapter, args).await? {
+        let Some(properties) = peripheral.properties().await.map_err(Error::Btle)? else {
+            continue;
+        };
+
+        let name = properties.local_name.unwrap_or_default();
+        let named = args.name.is_empty() || args.name.iter().any(|n| name.contains(n.as_str()));
+        let addressed = args.mac.is_empty() || args.mac.contains(&properties.address);
+        if !named || !addressed {
+            continue;
+        }
+
+        let mut inspection = Inspection {
+            by_name: lighthouse::classify(&name),
+            name,
+            address: properties.address,
+            by_services: None,
+            result: None,
+        };
+
+        let id = peripheral.id();
+        match Connection::open(adapter, &id, args.connect_timeout()).await {
+            Ok(connection) => {
+                inspection.by_services = lighthouse::classify_services(&connection.services());
+                if let Err(error) = connection.disconnect().await {
+                    warn!("{}: {error}", inspection.name);
+                }
+            }
+            Err(error) => inspection.result = Some(error.to_string()),
+        }
+
+        let Inspection {
+            name,
+            address,
+            by_name,
+            by_services,
+            result,
+        } = &inspection;
+        let version = |version: &Option<Version>| match version {
+            Some(version) => version.to_string(),
+            None => "unknown".into(),
+        };
+        if by_services.is_some() && by_name != by_services {
+            let (named, served) = (version(by_name), version(by_services));
+            warn!("{name} is named like a {named} basestation but has the services of a {served}");
+        }
+
+        if !args.json {
+            let services = match result {
+                Some(error) => format!("services unknown, {error}"),
+                None => format!("{} by services", version(by_services)),
+            };
+            println!("{name} {address}: {} by name, {services}", version(by_name));
+        }
+
+        inspections.push(inspection);
+    }
+
+    if inspections.is_empty() {
+        return Err(Error::NotFound);
+    }
+
+    if args.json {
+        let json = serde_json::to_string(&inspections).map_err(Error::Json)?;
+        println!("{json}");
+    }
+
+    Ok(())
+}
+
+/// The bytes as colon separated hex, e.g. `12:00:0A`
+fn hex(data: &[u8]) -> String {
+    let bytes = data.iter().map(|byte| format!("{byte:02X}"));
+    bytes.collect::<Vec<_>>().join(":")
+}
+
+/// The bytes as ASCII, with a dot for each unprintable byte
+fn ascii(data: &[u8]) -> String {
+    data.iter()
+        .map(|&byte| match byte {
+            0x20..=0x7E => byte as char,
+            _ => '.',
+        })
+        .collect()
+}
+
+/// Send the requested state to each matching basestation, on every adapter with --all-adapters
+///
+/// Each basestation is controlled by the first adapter to discover it, the others skip it.
+async fn control(
+    client: &Lighthouse,
+    args: &Args,
+    state: State,
+    bsid: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(bsid) = bsid {
+        lighthouse::parse_bsid(bsid)?;
+    }
+
+    // Basestations that succeeded stay claimed, so later passes only retry the others
+    let claimed = Mutex::new(HashSet::new());
+    let stopped = AtomicBool::new(false);
+    let mut reports = Vec::<Report>::new();
+    for pass in 1..=args.passes {
+        let controlled = control_pass(client, args, state, bsid, &claimed, &stopped).await;
+        let controlled = match controlled {
+            Ok(controlled) => controlled,
+            Err(error) if pass == args.passes && reports.is_empty() => return Err(error),
+            Err(error) => {
+                warn!("Pass {pass} of {} failed: {error}", args.passes);
+                Vec::new()
+            }
+        };
+
+        // With --fail-fast a failure also ends the passes, not only the pass it happened in
+        let failed = args.fail_fast && !controlled.iter().all(controlled_ok);
+        for report in controlled {
+            reports.retain(|known| known.address != report.address);
+            reports.push(report);
+        }
+
+        // Ctrl-C ends every pass, not only the one it interrupted
+        if failed || stopped.load(Ordering::Relaxed) || complete(args, &reports) {
+            break;
+        }
+
+        for report in reports.iter().filter(|report| !controlled_ok(report)) {
+            claimed.lock().unwrap().remove(&report.address);
+        }
+
+        if pass < args.passes {
+            info!(
+                "Pass {pass} of {} left basestations behind, scanning again",
+                args.passes
+            );
+        }
+    }
+
+    finish_control(args, reports).await?;
+    if stopped.load(Ordering::Relaxed) {
+        return Err(Error::Message("Interrupted"));
+    }
 
-use btleplug::{
-    api::{Central, Manager as _, Peripheral, ScanFilter},
-    platform::Manager,
-};
-use clap::Parser;
-use lighthouse::Error;
-use tokio::time;
-use tracing::info;
-use uuid::Uuid;
+    Ok(())
+}
 
-#[derive(Debug, Parser)]
-struct Args {
-    /// V1: [OFF|ON] [BSID] | V2: [OFF|ON|STANDBY]
-    #[arg(short, long)]
-    state: String,
+/// Whether another --passes pass has nothing left to do
+fn complete(args: &Args, reports: &[Report]) -> bool {
+    let expected = args
+        .expect
+        .is_none_or(|expect| succeeded(reports) >= expect);
+    let counted = args.count.is_some_and(|count| succeeded(reports) >= count);
+    let reached = args
+        .mac
+        .iter()
+        .all(|mac| reports.iter().any(|report| report.address == *mac));
 
-    /// V1: Basestation BSID
-    #[arg(short, long)]
-    bsid: Option<String>,
+    counted || (!reports.is_empty() && reports.iter().all(controlled_ok) && expected && reached)
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    let args = Args::parse();
+/// Whether the basestation of the report was controlled successfully
+fn controlled_ok(report: &Report) -> bool {
+    report.result.as_ref().is_some_and(Outcome::is_ok)
+}
 
-    let manager = Manager::new().await.map_err(Error::Btle)?;
-    let adapters = manager.adapters().await.map_err(Error::Btle)?;
-    if adapters.is_empty() {
-        return Err(Error::Message("No Bluetooth adapters found"));
+/// Scan and control the basestations once, with every adapter when --all-adapters is given
+async fn control_pass(
+    client: &Lighthouse,
+    args: &Args,
+    state: State,
+    bsid: Option<&str>,
+    claimed: &Mutex<HashSet<BDAddr>>,
+    stopped: &AtomicBool,
+) -> Result<Vec<Report>, Error> {
+    if !args.all_adapters {
+        return control_adapter(client.adapter(), args, state, bsid, claimed, stopped).await;
     }
 
-    for adapter in adapters.iter() {
-        let info = adapter.adapter_info().await.map_err(Error::Btle)?;
-        info!("Starting scan on {info}...");
+    let adapters = client.adapters().await?;
+    let controlled = adapters
+        .iter()
+        .map(|adapter| control_adapter(adapter, args, state, bsid, claimed, stopped));
+
+    let mut reports = Vec::new();
+    let mut failure = None;
+    let mut succeeded = false;
+    for (index, result) in future::join_all(controlled).await.into_iter().enumerate() {
+        match result {
+            Ok(controlled) => {
+                succeeded = true;
+                reports.extend(controlled);
+            }
+            Err(error) => {
+                warn!("Failed to control basestations with adapter {index}: {error}");
+                failure = Some(error);
+            }
+        }
+    }
 
-        adapter
-            .start_scan(ScanFilter::default())
-            .await
-            .map_err(Error::Btle)
-            .expect("Can't scan BLE adapter for connected devices...");
-        time::sleep(Duration::from_secs(10)).await;
+    if let (false, Some(error)) = (succeeded, failure) {
+        return Err(error);
+    }
 
-        let peripherals = adapter.peripherals().await.map_err(Error::Btle)?;
-        if peripherals.is_empty() {
-            return Err(Error::Message(
-                "->>> BLE peripheral devices were not found. Exiting...",
-            ));
+    Ok(reports)
+}
+
+/// Print the reports, failing if no basestation was controlled
+async fn finish_control(args: &Args, reports: Vec<Report>) -> Result<(), Error> {
+    let found = !reports.is_empty();
+    finish(args, reports).await?;
+    if !found {
+        return Err(Error::NotFound);
+    }
+
+    Ok(())
+}
+
+/// Send the requested state to each matching basestation as the adapter discovers it
+///
+/// Discovery stops at a fixed deadline --timeout seconds after the scan starts, or earlier once
+/// --count basestations were controlled. With --settle-then-act nothing is sent before the
+/// deadline, so the basestations are controlled together rather than as they come in. With
+/// --extend each basestation found pushes the deadline back, up to --max-timeout. Basestations
+/// that are still being controlled at the deadline are awaited for a bounded time before the
+/// results are returned, and any that never finish are reported as timed out rather than dropped.
+/// Basestations whose address is already in `claimed` are skipped, and `stopped` is set when
+/// Ctrl-C cut the scan short.
+async fn control_adapter(
+    adapter: &Adapter,
+    args: &Args,
+    state: State,
+    bsid: Option<&str>,
+    claimed: &Mutex<HashSet<BDAddr>>,
+    stopped: &AtomicBool,
+) -> Result<Vec<Report>, Error> {
+    let options = args.options();
+
+    let mut handled = HashSet::new();
+    let mut reports = Vec::new();
+    if !args.address.is_empty() || !args.cached.is_empty() {
+        reports = direct(adapter, args, state, bsid, &options, claimed, &mut handled).await?;
+
+        let reached = |address: &BDAddr| reports.iter().any(|report| report.address == *address);
+        if args.address.iter().chain(&args.cached).all(reached) {
+            return Ok(reports);
         }
+    }
+
+    let mut events = adapter.events().await.map_err(Error::Btle)?;
+    start_scan(adapter, args).await?;
+
+    let started = Instant::now();
+    let deadline = args.deadline();
+    if args.settle_then_act || args.nearest {
+        // Everything is found by the end of the scan, the loop below only has to act on it
+        time::sleep_until(deadline).await;
+        adapter.stop_scan().await.map_err(Error::Btle)?;
+    }
+
+    let deadline = time::sleep_until(deadline);
+    let interrupt = signal::ctrl_c();
+    let rescan_after = Duration::from_secs(args.rescan_after.unwrap_or_default());
+    let rescan_window = time::sleep(rescan_after);
+    let mut rescans = 0;
+    tokio::pin!(deadline, interrupt, rescan_window);
 
+    // Peripherals the adapter already knows about won't be discovered again
+    let mut peripherals = adapter.peripherals().await.map_err(Error::Btle)?;
+    peripherals.sort_by_key(Peripheral::address);
+    if args.nearest {
+        let mut stations = Vec::new();
         for peripheral in peripherals.iter() {
-            let Some(properties) = peripheral.properties().await.map_err(Error::Btle)? else {
-                continue;
+            if let Some(station) = BaseStation::from_peripheral(peripheral).await? {
+                if args.matches(&station) {
+                    stations.push(station);
+                }
+            }
+        }
+
+        let nearest = nearest(stations).map(|station| station.id);
+        peripherals.retain(|peripheral| nearest.as_ref() == Some(&peripheral.id()));
+    }
+    let mut pending = peripherals
+        .iter()
+        .map(Peripheral::id)
+        .collect::<VecDeque<_>>();
+
+    // Too many simultaneous connections make BlueZ fail every write. Tasks are spawned as soon as
+    // a basestation is discovered and wait for a permit themselves, so the loop reading the
+    // discovery events never waits on a busy connection and no event is missed
+    let permits = Arc::new(Semaphore::new(args.max_concurrent as usize));
+    let mut tasks = JoinSet::new();
+    let mut running = HashMap::new();
+    // Peripherals that may still turn out to be basestations once their name arrives
+    let mut unnamed = HashSet::new();
+    loop {
+        if args.count.is_some_and(|count| succeeded(&reports) >= count) {
+            debug!(
+                "Reached {} basestations, stopping early",
+                succeeded(&reports)
+            );
+            break;
+        }
+
+        let can_rescan =
+            args.rescan_after.is_some() && handled.is_empty() && rescans < args.max_rescans;
+        let id = match pending.pop_front() {
+            Some(id) => id,
+            None => tokio::select! {
+                // Nothing discovered after the deadline is controlled, even if it is already queued
+                biased;
+
+                () = &mut deadline => {
+                    if let Some(count) = args.count {
+                        warn!("Only reached {} of {count} basestations", succeeded(&reports));
+                    }
+
+                    break;
+                }
+                result = &mut interrupt => {
+                    result.map_err(Error::Io)?;
+                    stopped.store(true, Ordering::Relaxed);
+                    return interrupted(adapter, &handled, &mut tasks, running, reports).await;
+                }
+                Some(joined) = tasks.join_next_with_id(), if !tasks.is_empty() => {
+                    reports.push(joined_report(&mut running, joined));
+                    if fails_fast(args, &reports) {
+                        abort(adapter, &mut tasks, &running).await;
+                        for (_, report) in running.drain() {
+                            let skipped = Error::Message("Skipped by --fail-fast");
+                            reports.push(handle(report, Err(skipped)));
+                        }
+
+                        break;
+                    }
+
+                    continue;
+                }
+                () = &mut rescan_window, if can_rescan => {
+                    rescans += 1;
+                    rescan(adapter, args).await?;
+                    rescan_window.as_mut().reset(Instant::now() + rescan_after);
+                    continue;
+                }
+                event = events.next() => match event {
+                    Some(CentralEvent::DeviceDiscovered(id)) => id,
+                    Some(CentralEvent::DeviceUpdated(id)) if unnamed.contains(&id) => id,
+                    Some(_) => continue,
+                    None => break,
+                },
+            },
+        };
+
+        if handled.contains(&id) {
+            debug!("Skipping {id}, already handled");
+            continue;
+        }
+
+        debug!("Discovered {id}");
+        let Some(station) = target(adapter, args, &id, bsid, state, &mut handled).await? else {
+            if !has_name(adapter, &id).await {
+                debug!("{id} has no name yet, waiting for it to advertise one");
+                unnamed.insert(id);
+            }
+
+            continue;
+        };
+
+        unnamed.remove(&id);
+        handled.insert(id);
+
+        if !claimed.lock().unwrap().insert(station.address) {
+            debug!("Skipping {}, claimed by another adapter", station.name);
+            continue;
+        }
+
+        let mut report = Report::new(&station);
+        report.state = Some(state);
+
+        let adapter = adapter.clone();
+        let bsid = station_bsid(args, &station, bsid);
+        let options = options.clone();
+        let permits = permits.clone();
+        let settle = Duration::from_millis(args.settle);
+        let budget = args.control_timeout();
+        let task = tasks.spawn(async move {
+            // Some adapters fail to connect while still processing the advertisement
+            time::sleep(settle).await;
+
+            // A basestation that hangs mid-write must not hold its permit forever
+            let _permit = permits.acquire_owned().await;
+            let result =
+                lighthouse::set_state(&adapter, &station, state, bsid.as_deref(), &options);
+            if let Ok(result) = time::timeout(budget, result).await {
+                return result;
+            }
+
+            // The write was dropped mid-connection, which leaves the peripheral connected
+            lighthouse::disconnect(&adapter, &station.id).await.ok();
+            Err(Error::Timeout {
+                id:        station.id.clone(),
+                operation: "controlling",
+            })
+        });
+        running.insert(task.id(), report);
+
+        // Keep scanning while basestations are still showing up
+        if let Some(extend) = args.extend {
+            let cap = started + Duration::from_secs(args.max_timeout);
+            let extended = (Instant::now() + Duration::from_secs(extend)).min(cap);
+            if extended > deadline.deadline() {
+                debug!("Extending the scan by {extend} seconds");
+                deadline.as_mut().reset(extended);
+            }
+        }
+    }
+
+    // Wait for the basestations that are still being controlled, queued ones included
+    let rounds = tasks.len().div_ceil(args.max_concurrent as usize) as u32;
+    let drain = args.control_timeout().saturating_mul(rounds);
+    let drain = drain.saturating_add(Duration::from_millis(args.settle));
+    let drained = time::timeout(drain, async {
+        while let Some(joined) = tasks.join_next_with_id().await {
+            reports.push(joined_report(&mut running, joined));
+        }
+    })
+    .await;
+
+    if drained.is_err() {
+        warn!("{} basestations did not finish in time", running.len());
+        abort(adapter, &mut tasks, &running).await;
+        for (_, report) in running.drain() {
+            let error = Error::Timeout {
+                id:        report.id.clone(),
+                operation: "controlling",
             };
+            reports.push(handle(report, Err(error)));
+        }
+    }
+
+    Ok(reports)
+}
+
+/// Apply the steps in order, waiting between them
+///
+/// Every basestation named by a step has to be found by the scan before anything is sent.
+async fn sequence(
+    adapter: &Adapter,
+    args: &Args,
+    steps: &[Step],
+    bsid: Option<&str>,
+) -> Result<(), Error> {
+    if steps.is_empty() {
+        return Err(Error::Message("No sequence steps were given"));
+    }
+
+    if let Some(bsid) = bsid {
+        lighthouse::parse_bsid(bsid)?;
+    }
+
+    let options = args.options();
+
+    let stations = stations(adapter, args).await?;
+    let matching = |name: &str| {
+        stations
+            .iter()
+            .filter(move |station| station.name.contains(name))
+            .collect::<Vec<_>>()
+    };
+
+    for step in steps.iter() {
+        if let Step::Set { name, .. } = step {
+            if matching(name).is_empty() {
+                return Err(Error::Owned(format!(
+                    "No basestation matching {name} in the sequence was found"
+                )));
+            }
+        }
+    }
+
+    let mut reports = Vec::new();
+    for step in steps.iter() {
+        match step {
+            Step::Wait(delay) => {
+                debug!("Waiting {delay:?}");
+                time::sleep(*delay).await;
+            }
+            Step::Set { name, state } => {
+                for station in matching(name) {
+                    let mut report = Report::new(station);
+                    report.state = Some(*state);
+
+                    let bsid = station_bsid(args, station, bsid);
+                    let result =
+                        lighthouse::set_state(adapter, station, *state, bsid.as_deref(), &options);
+                    reports.push(handle(report, result.await));
+                    if fails_fast(args, &reports) {
+                        return finish(args, reports).await;
+                    }
+                }
+            }
+        }
+    }
+
+    finish(args, reports).await
+}
+
+/// V2: Keep scanning, poll the state of each basestation every interval and print the changes
+///
+/// A basestation that can no longer be read or that the adapter forgot about is reported as
+/// gone, and reported again once it comes back. Runs until interrupted with Ctrl-C.
+async fn monitor(adapter: &Adapter, args: &Args, interval: Duration) -> Result<(), Error> {
+    start_scan(adapter, args).await?;
+
+    let start = Instant::now();
+    let mut ticks = time::interval(interval);
+    let interrupt = signal::ctrl_c();
+    tokio::pin!(interrupt);
+
+    let mut states = HashMap::<PeripheralId, State>::new();
+    let mut names = HashMap::<PeripheralId, String>::new();
+    loop {
+        tokio::select! {
+            result = &mut interrupt => {
+                result.map_err(Error::Io)?;
+                info!("Interrupted, stopping scan...");
+                return adapter.stop_scan().await.map_err(Error::Btle);
+            }
+            _ = ticks.tick() => {}
+        }
 
-            let Some(name) = properties.local_name else {
+        let peripherals = match adapter.peripherals().await {
+            Ok(peripherals) => peripherals,
+            Err(error) => {
+                warn!("Failed to list the discovered peripherals: {error}");
                 continue;
-            };
+            }
+        };
 
-            let state = args.state.to_uppercase();
-            if let Some(bsid) = &args.bsid {
-                if !name.starts_with("HTC BS")
-                    || name[(name.len() - 4)..] != bsid[(bsid.len() - 4)..]
-                {
+        let mut seen = HashSet::new();
+        for peripheral in &peripherals {
+            // Not marked as seen, so a basestation that can't be looked up is reported as gone
+            let station = match BaseStation::from_peripheral(peripheral).await {
+                Ok(Some(station)) => station,
+                Ok(None) => continue,
+                Err(error) => {
+                    debug!("Failed to look up {}: {error}", peripheral.id());
                     continue;
                 }
+            };
+
+            if station.version != Version::V2 || !args.matches(&station) {
+                continue;
+            }
 
-                let aa = u8::from_str_radix(&bsid[0..2], 16).map_err(Error::Std)?;
-                let bb = u8::from_str_radix(&bsid[2..4], 16).map_err(Error::Std)?;
-                let cc = u8::from_str_radix(&bsid[4..6], 16).map_err(Error::Std)?;
-                let dd = u8::from_str_radix(&bsid[6..8], 16).map_err(Error::Std)?;
-
-                let cmd = match state.as_str() {
-                    "OFF" => vec![
-                        0x12, 0x02, 0x00, 0x01, dd, cc, bb, aa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                    ],
-                    "ON" => vec![
-                        0x12, 0x00, 0x00, 0x00, dd, cc, bb, aa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                    ],
-                    _ => {
-                        return Err(Error::Message(
-                            "V1: Unknown State {state}, Available: [OFF|ON]",
-                        ))
+            let elapsed = start.elapsed().as_secs();
+            let label = format!("{} {}", station.name, station.address);
+            match lighthouse::get_state(adapter, &station, args.connect_timeout()).await {
+                Ok(state) => {
+                    seen.insert(station.id.clone());
+                    match states.insert(station.id.clone(), state) {
+                        Some(last) if last == state => {}
+                        Some(last) => println!("{elapsed:>6}s {label}: {last} -> {state}"),
+                        None => println!("{elapsed:>6}s {label}: {state}"),
                     }
-                };
+                    names.insert(station.id, label);
+                }
+                Err(error) => debug!("Failed to read the state of {label}: {error}"),
+            }
+        }
+
+        let elapsed = start.elapsed().as_secs();
+        states.retain(|id, _| {
+            let gone = !seen.contains(id);
+            if gone {
+                let label = names.remove(id).unwrap_or_else(|| id.to_string());
+                println!("{elapsed:>6}s {label}: gone");
+            }
+
+            !gone
+        });
+    }
+}
+
+/// Keep scanning and set each basestation to the state whenever it is found in another
+///
+/// Basestations are checked when they are discovered or advertise again, at most once per
+/// interval, and every basestation the adapter knows about is re-checked each interval. Runs until
+/// interrupted with Ctrl-C.
+async fn watch(
+    adapter: &Adapter,
+    args: &Args,
+    state: State,
+    bsid: Option<&str>,
+    interval: Duration,
+    notify: bool,
+) -> Result<(), Error> {
+    if let Some(bsid) = bsid {
+        lighthouse::parse_bsid(bsid)?;
+    }
+
+    let options = args.options();
+
+    #[cfg(feature = "metrics")]
+    let metrics = match &args.command {
+        Command::Watch {
+            metrics_addr: Some(address),
+            ..
+        } => Some(metrics::spawn(*address).await?),
+        _ => None,
+    };
+
+    let mut events = adapter.events().await.map_err(Error::Btle)?;
+    start_scan(adapter, args).await?;
+
+    let mut ticks = time::interval(interval);
+    let interrupt = signal::ctrl_c();
+    tokio::pin!(interrupt);
 
-                const UUID: &str = "0000cb01-0000-1000-8000-00805f9b34fb";
-                let uuid = Uuid::parse_str(UUID).map_err(Error::Uuid)?;
+    let mut checked = HashMap::<PeripheralId, Instant>::new();
+    let mut skipped = HashSet::new();
+    // V2 basestations followed with --notify, polled again once their subscription ends
+    let mut held = HashMap::<task::Id, PeripheralId>::new();
+    let mut subscriptions = JoinSet::new();
+    loop {
+        let ids = tokio::select! {
+            result = &mut interrupt => {
+                result.map_err(Error::Io)?;
+                info!("Interrupted, stopping scan...");
+                subscriptions.abort_all();
+                for id in held.values() {
+                    lighthouse::disconnect(adapter, id).await.ok();
+                }
 
-                lighthouse::write(adapter, peripheral.id(), &cmd, uuid).await?;
-            } else {
-                if !name.starts_with("LHB-") {
+                return adapter.stop_scan().await.map_err(Error::Btle);
+            }
+            Some(joined) = subscriptions.join_next_with_id(), if !subscriptions.is_empty() => {
+                released(&mut held, joined);
+                continue;
+            }
+            _ = ticks.tick() => match adapter.peripherals().await {
+                Ok(peripherals) => peripherals.iter().map(Peripheral::id).collect(),
+                Err(error) => {
+                    warn!("Failed to list the discovered peripherals: {error}");
                     continue;
                 }
+            },
+            event = events.next() => match event {
+                Some(CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id)) => vec![id],
+                Some(_) => continue,
+                None => return Err(Error::Message("Bluetooth adapter stopped reporting events")),
+            },
+        };
 
-                let cmd = match state.as_str() {
-                    "OFF" => vec![0x00],
-                    "ON" => vec![0x01],
-                    "STANDBY" => vec![0x02],
-                    _ => {
-                        return Err(Error::Message(
-                            "V2: Unknown State {state}, Available: [OFF|ON|STANDBY]",
-                        ))
-                    }
-                };
+        for id in ids {
+            if held.values().any(|held| *held == id) {
+                continue;
+            }
+
+            if checked
+                .get(&id)
+                .is_some_and(|last| last.elapsed() < interval)
+            {
+                continue;
+            }
+
+            // A peripheral that can't be looked up is tried again once the interval passed
+            let station = match target(adapter, args, &id, bsid, state, &mut skipped).await {
+                Ok(Some(station)) => station,
+                Ok(None) => continue,
+                Err(error) => {
+                    checked.insert(id.clone(), Instant::now());
+                    warn!("{id}: {error}");
+                    continue;
+                }
+            };
+
+            checked.insert(id, Instant::now());
+
+            let bsid = station_bsid(args, &station, bsid);
+            let result = enforce(adapter, &station, state, bsid.as_deref(), &options).await;
+
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &metrics {
+                metrics.record(&station, state, &result);
+            }
+
+            if notify && station.version == Version::V2 && result.is_ok() {
+                let follow = hold(
+                    adapter.clone(),
+                    station.clone(),
+                    state,
+                    options.clone(),
+                    #[cfg(feature = "metrics")]
+                    metrics.clone(),
+                );
+                let task = subscriptions.spawn(follow);
+                held.insert(task.id(), station.id.clone());
+            }
+
+            match result {
+                Ok(false) => {}
+                Ok(true) => info!(
+                    device = %station.name,
+                    address = %station.address,
+                    state = %state,
+                    result = "ok",
+                    "Enforced {state} on {}",
+                    station.name
+                ),
+                Err(error) => warn!(
+                    device = %station.name,
+                    address = %station.address,
+                    state = %state,
+                    result = %error,
+                    "{}: {error}",
+                    station.name
+                ),
+            }
+        }
+    }
+}
+
+/// V2: Keep the basestation in the state by reacting to the changes it pushes
+///
+/// Runs until the connection drops, after which the basestation is polled again. Each pushed state
+/// and correction is recorded in the --metrics-addr metrics.
+async fn hold(
+    adapter: Adapter,
+    station: BaseStation,
+    state: State,
+    options: Options,
+    #[cfg(feature = "metrics")] metrics: Option<Arc<metrics::Metrics>>,
+) -> Result<(), Error> {
+    let connection = Connection::open(&adapter, &station.id, options.timeout).await?;
+    let changes = connection.subscribe(lighthouse::V2_UUID).await?;
+    tokio::pin!(changes);
+    info!("Following the state of {}", station.name);
+
+    // The stream of changes doesn't end when the connection drops
+    let mut ticks = time::interval(Duration::from_secs(5));
+    loop {
+        let value = tokio::select! {
+            _ = ticks.tick() => {
+                if !connection.is_connected().await? {
+                    return Err(Error::Message("Lost the connection"));
+                }
+
+                continue;
+            }
+            Some(value) = changes.next() => value,
+        };
+
+        // Booting and other transient states aren't worth reacting to
+        let current = match lighthouse::parse_v2_state(&value) {
+            Ok(current) => current,
+            Err(error) => {
+                debug!("{}: {error}", station.name);
+                continue;
+            }
+        };
+
+        if current == state {
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &metrics {
+                metrics.record(&station, state, &Ok(false));
+            }
+
+            continue;
+        }
+
+        info!(
+            device = %station.name,
+            address = %station.address,
+            state = %state,
+            "{} changed to {current}, setting it back to {state}",
+            station.name
+        );
+        if options.dry_run {
+            info!("Would set {} to {state}", station.name);
+            continue;
+        }
+
+        let command = lighthouse::v2_command(state);
+        let written = connection.write(lighthouse::V2_UUID, &command).await;
+        let written = written.map(|()| true);
+
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &metrics {
+            metrics.record(&station, state, &written);
+        }
+
+        written?;
+    }
+}
+
+/// Log why following a basestation with --notify stopped
+fn released(
+    held: &mut HashMap<task::Id, PeripheralId>,
+    joined: Result<(task::Id, Result<(), Error>), JoinError>,
+) {
+    let (task, result) = match joined {
+        Ok((task, result)) => (task, result),
+        Err(error) => (
+            error.id(),
+            Err(Error::Message("Subscription task panicked")),
+        ),
+    };
+
+    let id = held.remove(&task).expect("task is held");
+    if let Err(error) = result {
+        warn!("Stopped following {id}, polling it again: {error}");
+    }
+}
+
+/// Set the basestation to the state unless it is already in it, V1 can't be read so is always set
+///
+/// Returns whether the state was written.
+async fn enforce(
+    adapter: &Adapter,
+    station: &BaseStation,
+    state: State,
+    bsid: Option<&str>,
+    options: &Options,
+) -> Result<bool, Error> {
+    if station.version == Version::V2
+        && lighthouse::get_state(adapter, station, options.timeout).await? == state
+    {
+        debug!("{} is already {state}", station.name);
+        return Ok(false);
+    }
+
+    lighthouse::set_state(adapter, station, state, bsid, options).await?;
+    Ok(true)
+}
+
+/// Stop scanning and disconnect from the basestations still being controlled after Ctrl-C
+///
+/// A scan or connection left running when the process exits can wedge the adapter. The
+/// basestations that were still being controlled are reported as interrupted.
+async fn interrupted(
+    adapter: &Adapter,
+    handled: &HashSet<PeripheralId>,
+    tasks: &mut JoinSet<Result<(), Error>>,
+    running: HashMap<task::Id, Report>,
+    mut reports: Vec<Report>,
+) -> Result<Vec<Report>, Error> {
+    warn!("Interrupted, disconnecting...");
+
+    // Stopped first, so no task reconnects or writes after its basestation was disconnected
+    tasks.abort_all();
+    while tasks.join_next().await.is_some() {}
+
+    adapter.stop_scan().await.map_err(Error::Btle)?;
+
+    let done = reports
+        .iter()
+        .map(|report| &report.id)
+        .collect::<HashSet<_>>();
+    for id in handled.iter().filter(|id| !done.contains(id)) {
+        if let Err(error) = lighthouse::disconnect(adapter, id).await {
+            warn!("Failed to disconnect from {id}: {error}");
+        }
+    }
+
+    for (_, mut report) in running {
+        report.result = Some(Outcome::Interrupted);
+        reports.push(report);
+    }
+
+    Ok(reports)
+}
+
+/// Abort the control tasks and disconnect from the basestations they were controlling
+///
+/// An aborted task is dropped mid-connection, which leaves the peripheral connected.
+async fn abort(
+    adapter: &Adapter,
+    tasks: &mut JoinSet<Result<(), Error>>,
+    running: &HashMap<task::Id, Report>,
+) {
+    tasks.abort_all();
+    while tasks.join_next().await.is_some() {}
+
+    for report in running.values() {
+        if let Err(error) = lighthouse::disconnect(adapter, &report.id).await {
+            warn!("Failed to disconnect from {}: {error}", report.name);
+        }
+    }
+}
+
+/// The report of a finished control task
+fn joined_report(
+    running: &mut HashMap<task::Id, Report>,
+    joined: Result<(task::Id, Result<(), Error>), JoinError>,
+) -> Report {
+    match joined {
+        Ok((id, result)) => handle(running.remove(&id).expect("task is running"), result),
+        Err(error) => handle(
+            running.remove(&error.id()).expect("task is running"),
+            Err(Error::Message("Control task panicked")),
+        ),
+    }
+}
+
+/// Control the basestations given by --address or --use-cache without scanning for them
+///
+/// The adapter remembers peripherals from earlier scans, so known addresses can be connected to
+/// straight away. Those that fail or time out are left for the scan to pick up.
+async fn direct(
+    adapter: &Adapter,
+    args: &Args,
+    state: State,
+    bsid: Option<&str>,
+    options: &Options,
+    claimed: &Mutex<HashSet<BDAddr>>,
+    handled: &mut HashSet<PeripheralId>,
+) -> Result<Vec<Report>, Error> {
+    let mut reports = Vec::new();
+    let peripherals = adapter.peripherals().await.map_err(Error::Btle)?;
+    for peripheral in peripherals.iter() {
+        let address = peripheral.address();
+        if !args.address.contains(&address) && !args.cached.contains(&address) {
+            continue;
+        }
+
+        let id = peripheral.id();
+        let Some(station) = target(adapter, args, &id, bsid, state, handled).await? else {
+            continue;
+        };
+
+        // Claimed before writing, the other adapters run their direct connections at the same time
+        if !claimed.lock().unwrap().insert(station.address) {
+            debug!("Skipping {}, claimed by another adapter", station.name);
+            handled.insert(station.id);
+            continue;
+        }
+
+        let mut report = Report::new(&station);
+        let bsid = station_bsid(args, &station, bsid);
+        let result = lighthouse::set_state(adapter, &station, state, bsid.as_deref(), options);
+        // Bounded like controlling a discovered basestation, every retry included
+        match time::timeout(args.control_timeout(), result).await {
+            Ok(Ok(())) => {
+                handled.insert(station.id);
+                report.state = Some(state);
+                report.result = Some(Outcome::Ok);
+                reports.push(report);
+                continue;
+            }
+            Ok(Err(error)) => warn!("{}: {error}, scanning for it instead", report.name),
+            Err(_) => {
+                warn!(
+                    "Timed out connecting to {}, scanning for it instead",
+                    report.name
+                );
+                // The abandoned attempt may have left the connection open
+                lighthouse::disconnect(adapter, &station.id).await.ok();
+            }
+        }
+
+        // Released for the scan, whichever adapter finds it first
+        claimed.lock().unwrap().remove(&station.address);
+    }
+
+    Ok(reports)
+}
+
+/// Look up a discovered peripheral and check it is a basestation passing the filters
+///
+/// Basestations that can't be put into the state are skipped with a warning, once for each one
+/// added to `skipped`.
+async fn target(
+    adapter: &Adapter,
+    args: &Args,
+    id: &PeripheralId,
+    bsid: Option<&str>,
+    state: State,
+    skipped: &mut HashSet<PeripheralId>,
+) -> Result<Option<BaseStation>, Error> {
+    let peripheral = adapter.peripheral(id).await.map_err(Error::Btle)?;
+    let Some(station) = BaseStation::from_peripheral(&peripheral).await? else {
+        return Ok(None);
+    };
+
+    if !args.matches(&station) {
+        return Ok(None);
+    }
+
+    // Checked before the BSID, so V1 basestations are warned about even without one
+    if !station.version.supports(state) {
+        if skipped.insert(station.id.clone()) {
+            warn!(
+                "{} basestations don't support {state}, skipping {}",
+                station.version, station.name
+            );
+        }
+
+        return Ok(None);
+    }
+
+    if !bsid_matches(args, &station, bsid) {
+        return Ok(None);
+    }
+
+    Ok(Some(station))
+}
+
+/// Whether the peripheral advertised its name yet, some only do in a later advertisement
+async fn has_name(adapter: &Adapter, id: &PeripheralId) -> bool {
+    let Ok(peripheral) = adapter.peripheral(id).await else {
+        return false;
+    };
+
+    peripheral
+        .properties()
+        .await
+        .ok()
+        .flatten()
+        .is_some_and(|properties| properties.local_name.is_some())
+}
+
+/// V1: Whether the BSID belongs to the basestation, always true for V2 and --bsid-from-mac
+fn bsid_matches(args: &Args, station: &BaseStation, bsid: Option<&str>) -> bool {
+    if station.version == Version::V2 || args.bsid_from_mac {
+        return true;
+    }
+
+    let Some([_, _, cc, dd]) = bsid.and_then(|bsid| lighthouse::parse_bsid(bsid).ok()) else {
+        return false;
+    };
+
+    match bsid_suffix(&station.name) {
+        Ok(suffix) => suffix.eq_ignore_ascii_case(&format!("{cc:02x}{dd:02x}")),
+        Err(error) => {
+            warn!("Skipping {}: {error}", station.name);
+            false
+        }
+    }
+}
+
+/// V1: The BSID to control the basestation with, derived from its address with --bsid-from-mac
+fn station_bsid(args: &Args, station: &BaseStation, bsid: Option<&str>) -> Option<String> {
+    if station.version == Version::V1 && args.bsid_from_mac {
+        let [aa, bb, cc, dd] = lighthouse::bsid_from_address(station.address);
+        return Some(format!("{aa:02X}{bb:02X}{cc:02X}{dd:02X}"));
+    }
+
+    bsid.map(String::from)
+}
+
+/// Number of basestations that were controlled successfully
+fn succeeded(reports: &[Report]) -> usize {
+    reports
+        .iter()
+        .filter(|report| controlled_ok(report))
+        .count()
+}
+
+/// Whether --fail-fast stops at the last report, because it failed
+fn fails_fast(args: &Args, reports: &[Report]) -> bool {
+    args.fail_fast
+        && reports
+            .last()
+            .and_then(|report| report.result.as_ref())
+            .is_some_and(|result| !result.is_ok())
+}
+
+/// Record the result of controlling a basestation
+fn handle(mut report: Report, result: Result<(), Error>) -> Report {
+    report.result = Some(result.into());
+
+    let state = report.state.map(|state| state.to_string());
+    let result = report.result.as_ref().map(ToString::to_string);
+    info!(
+        device = %report.name,
+        address = %report.address,
+        state = state.as_deref().unwrap_or("-"),
+        result = result.as_deref().unwrap_or("-"),
+        "Controlled {}",
+        report.name
+    );
+
+    report
+}
+
+/// Remember the basestations in the cache and print the reports
+///
+/// Fails when any basestation could not be controlled, so scripts can detect partial failures.
+async fn finish(args: &Args, mut reports: Vec<Report>) -> Result<(), Error> {
+    // Basestations finish in whatever order they respond
+    reports.sort_by(|a, b| a.address.cmp(&b.address).then_with(|| a.name.cmp(&b.name)));
+
+    if args.command.controls() {
+        let found = reports
+            .iter()
+            .map(|report| (report.name.as_str(), report.address))
+            .collect::<Vec<_>>();
+        for (name, addresses) in args.ambiguous(&found) {
+            warn!("--name {name} matched more than one basestation: {addresses}");
+        }
+    }
+
+    let devices = reports.iter().filter_map(|report| {
+        Some(cache::Device {
+            name:          report.name.clone(),
+            address:       report.address,
+            peripheral_id: report.id.to_string(),
+            version:       report.version?,
+        })
+    });
 
-                const UUID: &str = "00001525-1212-efde-1523-785feabcd124";
-                let uuid = Uuid::parse_str(UUID).map_err(Error::Uuid)?;
+    if let Err(error) = cache::update(devices.collect()) {
+        warn!("Failed to update the device cache: {error}");
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let Some(url) = &args.mqtt {
+        let messages = reports
+            .iter()
+            .filter(|report| report.result.as_ref().is_none_or(Outcome::is_ok))
+            .filter_map(|report| {
+                report.state.map(|state| mqtt::Message {
+                    name: report.name.clone(),
+                    state,
+                })
+            });
+
+        if let Err(error) = mqtt::publish(url, messages.collect()).await {
+            warn!("Failed to publish to MQTT: {error}");
+        }
+    }
+
+    match args.format {
+        _ if args.quiet() && args.command.controls() => {}
+        Format::Plain => {}
+        Format::Table => table(&reports),
+        Format::Json => {
+            let json = serde_json::to_string(&reports).map_err(Error::Json)?;
+            println!("{json}");
+        }
+    }
 
-                lighthouse::write(adapter, peripheral.id(), &cmd, uuid).await?;
+    let failed = reports
+        .iter()
+        .filter(|report| report.result.as_ref().is_some_and(|result| !result.is_ok()))
+        .collect::<Vec<_>>();
+
+    let summarized = !args.json && !args.quiet();
+    if summarized && reports.iter().any(|report| report.result.is_some()) {
+        let color = args.color(&std::io::stdout());
+        let succeeded = format!("{} succeeded", succeeded(&reports));
+        let summary = format!("{} failed", failed.len());
+        let summary = match failed.len() {
+            0 => summary,
+            _ => paint(&summary, RED, color),
+        };
+        println!("{}, {summary}", paint(&succeeded, GREEN, color));
+
+        for report in reports.iter() {
+            let Some(result) = &report.result else {
+                continue;
             };
+
+            let shade = if result.is_ok() { GREEN } else { RED };
+            let result = paint(&result.to_string(), shade, color);
+            println!("  {} {}: {result}", report.name, report.address);
+        }
+    }
+
+    if let Some(expect) = args.expect {
+        let found = reports.len() - failed.len();
+        if summarized {
+            println!("Found {found} of {expect} basestations");
+        }
+
+        if found < expect {
+            warn!("Expected {expect} basestations but only {found} responded");
+            return Err(Error::Partial {
+                failed: expect - found,
+                total:  expect,
+            });
         }
     }
+
+    if !failed.is_empty() {
+        return Err(Error::Partial {
+            failed: failed.len(),
+            total:  reports.len(),
+        });
+    }
+
     Ok(())
 }
+
+const GREEN: &str = "32";
+const RED: &str = "31";
+
+/// Wrap the text in the ANSI escape codes for the color if coloring is enabled
+fn paint(text: &str, color: &str, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{color}m{text}\x1b[0m")
+    } else {
+        text.into()
+    }
+}
+
+/// Print the reports as aligned columns
+fn table(reports: &[Report]) {
+    let header = ["NAME", "ADDRESS", "VERSION", "STATE", "RSSI"].map(String::from);
+    let rows = reports.iter().map(|report| {
+        [
+            report.name.clone(),
+            report.address.to_string(),
+            report
+                .version
+                .map_or("-".into(), |version| version.to_string()),
+            report.state.map_or("-".into(), |state| state.to_string()),
+            report.rssi.map_or("-".into(), |rssi| format!("{rssi} dBm")),
+        ]
+    });
+
+    let rows = std::iter::once(header).chain(rows).collect::<Vec<_>>();
+    let mut widths = [0; 5];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    for row in rows.iter() {
+        let cells = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>();
+
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{extract::State as Shared, http::header, routing::get, Router};
+use btleplug::api::BDAddr;
+use lighthouse::{BaseStation, Error, State};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+/// What `lighthouse watch` last saw of a basestation
+#[derive(Debug, Default)]
+struct Station {
+    name:      String,
+    state:     Option<State>,
+    successes: u64,
+    failures:  u64,
+}
+
+/// Recorded by `lighthouse watch` and served on `/metrics` in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    stations: Mutex<BTreeMap<BDAddr, Station>>,
+}
+
+impl Metrics {
+    /// Record the outcome of holding the basestation in the state
+    pub fn record(&self, station: &BaseStation, state: State, result: &Result<bool, Error>) {
+        let mut stations = self.stations.lock().expect("metrics lock poisoned");
+        let entry = stations.entry(station.address).or_default();
+        entry.name.clone_from(&station.name);
+
+        match result {
+            Ok(written) => {
+                entry.state = Some(state);
+                if *written {
+                    entry.successes += 1;
+                }
+            }
+            Err(_) => {
+                entry.state = None;
+                entry.failures += 1;
+            }
+        }
+    }
+
+    fn render(&self) -> String {
+        let stations = self.stations.lock().expect("metrics lock poisoned");
+        let mut text = String::new();
+
+        text.push_str("# HELP lighthouse_station_state Power state, 0 off, 1 on, 2 standby\n");
+        text.push_str("# TYPE lighthouse_station_state gauge\n");
+        for (address, station) in stations.iter() {
+            let Some(state) = station.state else {
+                continue;
+            };
+
+            let value = match state {
+                State::Off => 0,
+                State::On => 1,
+                State::Standby => 2,
+            };
+            let labels = labels(&station.name, address);
+            writeln!(text, "lighthouse_station_state{{{labels}}} {value}").unwrap();
+        }
+
+        text.push_str("# HELP lighthouse_control_successes_total States written\n");
+        text.push_str("# TYPE lighthouse_control_successes_total counter\n");
+        for (address, station) in stations.iter() {
+            let labels = labels(&station.name, address);
+            let value = station.successes;
+            writeln!(
+                text,
+                "lighthouse_control_successes_total{{{labels}}} {value}"
+            )
+            .unwrap();
+        }
+
+        text.push_str("# HELP lighthouse_control_failures_total Failed reads or writes\n");
+        text.push_str("# TYPE lighthouse_control_failures_total counter\n");
+        for (address, station) in stations.iter() {
+            let labels = labels(&station.name, address);
+            let value = station.failures;
+            writeln!(
+                text,
+                "lighthouse_control_failures_total{{{labels}}} {value}"
+            )
+            .unwrap();
+        }
+
+        text
+    }
+}
+
+/// The name and address labels, with the name escaped
+fn labels(name: &str, address: &BDAddr) -> String {
+    let name = name.replace('\\', r"\\").replace('"', r#"\""#);
+    format!(r#"name="{name}",address="{address}""#)
+}
+
+/// Serve `/metrics` in the background for as long as the process runs
+pub async fn spawn(address: SocketAddr) -> Result<Arc<Metrics>, Error> {
+    let metrics = Arc::new(Metrics::default());
+    let router = Router::new()
+        .route("/metrics", get(render))
+        .with_state(metrics.clone());
+
+    let listener = TcpListener::bind(address).await.map_err(Error::Io)?;
+    info!("Serving metrics on http://{address}/metrics");
+
+    tokio::spawn(async move {
+        if let Err(error) = axum::serve(listener, router).await {
+            warn!("Metrics server stopped: {error}");
+        }
+    });
+
+    Ok(metrics)
+}
+
+/// `GET /metrics`: The metrics in the Prometheus text format
+async fn render(
+    Shared(metrics): Shared<Arc<Metrics>>,
+) -> ([(header::HeaderName, &'static str); 1], String) {
+    let content_type = (header::CONTENT_TYPE, "text/plain; version=0.0.4");
+    ([content_type], metrics.render())
+}
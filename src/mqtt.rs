@@ -0,0 +1,46 @@
+use lighthouse::{Error, State};
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, QoS};
+use serde::Serialize;
+use tracing::debug;
+
+/// A basestation state published to `lighthouse/<name>/state`
+#[derive(Debug, Serialize)]
+pub struct Message {
+    pub name:  String,
+    pub state: State,
+}
+
+/// Publish the states to the broker as retained messages, then disconnect
+pub async fn publish(url: &str, messages: Vec<Message>) -> Result<(), Error> {
+    let url = match url.contains("client_id=") {
+        true => url.to_string(),
+        false if url.contains('?') => format!("{url}&client_id=lighthouse"),
+        false => format!("{url}?client_id=lighthouse"),
+    };
+
+    let options =
+        MqttOptions::parse_url(url).map_err(|_| Error::Message("Invalid MQTT broker URL"))?;
+    let (client, mut eventloop) = AsyncClient::new(options, messages.len().max(1));
+
+    for message in messages.iter() {
+        let topic = format!("lighthouse/{}/state", message.name);
+        let payload = serde_json::to_vec(message).map_err(Error::Json)?;
+
+        debug!("Publishing {} to {topic}", message.state);
+        client
+            .publish(topic, QoS::AtLeastOnce, true, payload)
+            .await
+            .map_err(Error::Mqtt)?;
+    }
+
+    client.disconnect().await.map_err(Error::Mqtt)?;
+
+    // The event loop sends the queued requests in order, ending with the disconnect
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => return Ok(()),
+            Ok(_) => continue,
+            Err(error) => return Err(Error::MqttConnection(Box::new(error))),
+        }
+    }
+}
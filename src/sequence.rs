@@ -0,0 +1,101 @@
+use std::{str::FromStr, time::Duration};
+
+use lighthouse::{Error, State};
+
+/// A step of a sequence, e.g. `LHB-1A2B3C4D:on` or `2s`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    /// Set the basestations whose name contains this to the state
+    Set { name: String, state: State },
+    /// Wait before the next step
+    Wait(Duration),
+}
+
+impl FromStr for Step {
+    type Err = Error;
+
+    fn from_str(step: &str) -> Result<Self, Self::Err> {
+        let step = step.trim();
+        if let Some((name, state)) = step.split_once(':') {
+            if name.trim().is_empty() {
+                return Err(Error::Owned(format!(
+                    "Step {step} must name the basestations, like NAME:STATE"
+                )));
+            }
+
+            return Ok(Self::Set {
+                name:  name.to_string(),
+                state: State::from_str(state)?,
+            });
+        }
+
+        let (number, seconds) = match step.strip_suffix("ms") {
+            Some(number) => (number, 0.001),
+            None => match step.strip_suffix('s') {
+                Some(number) => (number, 1.0),
+                None => {
+                    return Err(Error::Owned(format!(
+                        "Step {step} must be NAME:STATE or a delay like 2s"
+                    )))
+                }
+            },
+        };
+
+        // Fractions are allowed, negative or endless delays aren't
+        number
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(|number| Duration::try_from_secs_f64(number * seconds).ok())
+            .map(Self::Wait)
+            .ok_or_else(|| {
+                Error::Owned(format!(
+                    "Step {step} is not a valid delay, use e.g. 2s, 1.5s or 500ms"
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_set() {
+        let step = Step::from_str(" LHB-1A2B3C4D:on ").unwrap();
+        assert_eq!(
+            step,
+            Step::Set {
+                name:  "LHB-1A2B3C4D".into(),
+                state: State::On,
+            }
+        );
+
+        assert!(Step::from_str("LHB-1A2B3C4D:bright").is_err());
+        assert!(Step::from_str(":on").is_err());
+    }
+
+    #[test]
+    fn parse_wait_units() {
+        assert_eq!(
+            Step::from_str("2s").unwrap(),
+            Step::Wait(Duration::from_secs(2))
+        );
+        assert_eq!(
+            Step::from_str("1.5s").unwrap(),
+            Step::Wait(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            Step::from_str("500ms").unwrap(),
+            Step::Wait(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn parse_wait_rejects_bad_delays() {
+        for step in ["2", "s", "-1s", "infs", "twos", "1e400s"] {
+            let error = Step::from_str(step).unwrap_err().to_string();
+            assert!(error.starts_with(&format!("Step {step}")), "{error}");
+        }
+    }
+}